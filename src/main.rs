@@ -1,9 +1,11 @@
 use chrono::prelude::*;
-use clap::{Arg, Command, ValueEnum};
-use serde::de;
+use clap::{Arg, Command};
+use serde::Deserialize;
 use serde_yaml::Value;
-use std::path::Path;
-use std::{fs, ops::Index};
+use std::collections::{BTreeMap, HashMap};
+use std::path::{Path, PathBuf};
+use std::rc::Rc;
+use std::fs;
 
 #[macro_export]
 macro_rules! print_error {
@@ -116,23 +118,49 @@ fn main() {
                 .global(true)
                 .action(clap::ArgAction::SetTrue),
         )
+        .arg(
+            Arg::new("now")
+                .long("now")
+                .value_name("DATE")
+                .help("Sets the current time of the date functions (e.g. 2024-01-31T12:00:00Z), for reproducible outputs")
+                .global(true),
+        )
+        .arg(
+            Arg::new("config")
+                .long("config")
+                .value_name("FILE")
+                .help("Adds a config file of get_config and lookup_config, over the discovered ones and YW_CONFIG; repeat it to merge in order, last wins")
+                .global(true)
+                .action(clap::ArgAction::Append),
+        )
+        .arg(
+            Arg::new("env-file")
+                .long("env-file")
+                .value_name("FILE")
+                .help("Loads the NAME=VALUE lines of a .env file into the env. namespace of templates; repeat it to load in order, last wins")
+                .global(true)
+                .action(clap::ArgAction::Append),
+        )
+        .arg(
+            Arg::new("env-allow")
+                .long("env-allow")
+                .value_name("PATTERN")
+                .help("Exposes the environment variables matching a pattern (e.g. AZURE_*) to templates, next to YW_*")
+                .global(true)
+                .action(clap::ArgAction::Append),
+        )
         .subcommand(
             Command::new("merge")
                 .about("Merges YAML files")
                 .arg(
-                    Arg::new("input1")
-                        .short('a')
-                        .long("input1")
-                        .value_name("FILE")
-                        .help("Sets the input 1 file or directory")
-                        .required(true),
-                )
-                .arg(
-                    Arg::new("input2")
-                        .short('b')
-                        .long("input2")
+                    Arg::new("input")
+                        .short('i')
+                        .long("input")
                         .value_name("FILE")
-                        .help("Sets the input 2 file or directory")
+                        .help("Adds an input file, directory or glob; repeat it to merge in order, last wins")
+                        .aliases(["input1", "input2"])
+                        .short_aliases(['a', 'b'])
+                        .action(clap::ArgAction::Append)
                         .required(true),
                 )
                 .arg(
@@ -142,6 +170,114 @@ fn main() {
                         .value_name("FILE")
                         .help("Sets the output file")
                         .required(true),
+                )
+                .arg(
+                    Arg::new("recursive")
+                        .short('r')
+                        .long("recursive")
+                        .help("Traverses input directories recursively")
+                        .action(clap::ArgAction::SetTrue),
+                )
+                .arg(
+                    Arg::new("include")
+                        .long("include")
+                        .value_name("PATTERN")
+                        .help("Glob pattern of files to take from input directories (default: *.yaml, *.yml)")
+                        .action(clap::ArgAction::Append),
+                )
+                .arg(
+                    Arg::new("exclude")
+                        .long("exclude")
+                        .value_name("PATTERN")
+                        .help("Glob pattern of files to skip in input directories")
+                        .action(clap::ArgAction::Append),
+                )
+                .arg(
+                    Arg::new("list-strategy")
+                        .long("list-strategy")
+                        .value_name("STRATEGY")
+                        .help("Sets how lists are merged")
+                        .default_value("append-unique")
+                        .value_parser(ListStrategy::NAMES),
+                )
+                .arg(
+                    Arg::new("list-strategy-at")
+                        .long("list-strategy-at")
+                        .value_name("PATH=STRATEGY")
+                        .help("Sets how the lists at a path are merged, the path can hold glob patterns (e.g. resources.*.tags=replace)")
+                        .action(clap::ArgAction::Append),
+                )
+                .arg(
+                    Arg::new("merge-key")
+                        .long("merge-key")
+                        .value_name("FIELD")
+                        .help("Sets the identity fields, tried in order, of list items merged by key (default: id, name)")
+                        .action(clap::ArgAction::Append),
+                )
+                .arg(
+                    Arg::new("merge-key-at")
+                        .long("merge-key-at")
+                        .value_name("PATH=FIELD")
+                        .help("Merges the list items at a path by the given identity field (e.g. azure.prefix=id)")
+                        .action(clap::ArgAction::Append),
+                )
+                .arg(
+                    Arg::new("null-overlay")
+                        .long("null-overlay")
+                        .value_name("MODE")
+                        .help("Sets what a null in a later file does: set the key to null or unset (remove) it")
+                        .default_value("set")
+                        .value_parser(NullOverlay::NAMES),
+                )
+                .arg(
+                    Arg::new("provenance")
+                        .long("provenance")
+                        .value_name("FORMAT")
+                        .help("Records the file, document and line that set each value, as a yaml or json sidecar file or as comments at the end of the output")
+                        .num_args(0..=1)
+                        .default_missing_value("yaml")
+                        .value_parser(["yaml", "json", "comments"]),
+                )
+                .arg(
+                    Arg::new("provenance-output")
+                        .long("provenance-output")
+                        .value_name("FILE")
+                        .help("Sets the provenance file (default: <output>.provenance.<format>)")
+                        .requires("provenance"),
+                )
+                .arg(
+                    Arg::new("conflicts")
+                        .long("conflicts")
+                        .value_name("FILE")
+                        .help("Reports every value a later file overrides, on the console or as yaml in FILE")
+                        .num_args(0..=1)
+                        .default_missing_value(""),
+                )
+                .arg(
+                    Arg::new("strict")
+                        .long("strict")
+                        .help("Fails the merge when a value changes type or a protected key is changed")
+                        .action(clap::ArgAction::SetTrue),
+                )
+                .arg(
+                    Arg::new("protect")
+                        .long("protect")
+                        .value_name("PATH")
+                        .help("Protects a path (glob patterns allowed) and everything below it from later files under --strict")
+                        .action(clap::ArgAction::Append),
+                )
+                .arg(
+                    Arg::new("strict-vars")
+                        .long("strict-vars")
+                        .help("Fails the merge when a placeholder refers to a path that is not in the data")
+                        .action(clap::ArgAction::SetTrue),
+                )
+                .arg(
+                    Arg::new("macros")
+                        .long("macros")
+                        .value_name("FILE")
+                        .help("Adds a file of template macros, next to the _macros: section of the inputs")
+                        .action(clap::ArgAction::Append),
                 ),
         )
         .subcommand(
@@ -164,13 +300,79 @@ fn main() {
                         .required(true),
                 ),
         )
+        .subcommand(
+            Command::new("functions")
+                .about("Lists the functions templates can call")
+                .arg(
+                    Arg::new("macros")
+                        .long("macros")
+                        .value_name("FILE")
+                        .help("Adds a file of template macros to the list")
+                        .action(clap::ArgAction::Append),
+                ),
+        )
+        .subcommand(
+            Command::new("query")
+                .about("Prints the value at a path of the merged YAML files")
+                .arg(
+                    Arg::new("input")
+                        .short('i')
+                        .long("input")
+                        .value_name("FILE")
+                        .help("Adds an input file, directory or glob; repeat it to merge in order, last wins")
+                        .action(clap::ArgAction::Append)
+                        .required(true),
+                )
+                .arg(
+                    Arg::new("path")
+                        .short('p')
+                        .long("path")
+                        .value_name("PATH")
+                        .help("Sets the path to print (e.g. azure.prefix[0].text, items[-1], azure.prefix[?id=='storage_account'].text)")
+                        .required(true),
+                )
+                .arg(
+                    Arg::new("format")
+                        .short('f')
+                        .long("format")
+                        .value_name("FORMAT")
+                        .help("Sets the output format")
+                        .default_value("yaml")
+                        .value_parser(["yaml", "json"]),
+                )
+                .arg(
+                    Arg::new("macros")
+                        .long("macros")
+                        .value_name("FILE")
+                        .help("Adds a file of template macros, next to the _macros: section of the inputs")
+                        .action(clap::ArgAction::Append),
+                ),
+        )
         .get_matches();
 
-    //let config = read_config_file();
-    //println!("Configuration loaded var1: {:?}", config["var1"].as_str().unwrap());
+    if let Some(now) = matches.get_one::<String>("now") {
+        let now = parse_date(&Value::String(now.clone())).unwrap_or_else(|e| {
+            print_error!("Invalid --now: {}", e);
+            std::process::exit(1);
+        });
+        NOW.set(now.with_timezone(&Utc)).unwrap();
+    }
 
-    //let environment_variables = load_environment_variables();
-    //println!("Environment variables ALLUSERSPROFILE: {:?}", environment_variables["ALLUSERSPROFILE"].as_str().unwrap());
+    let config_files: Vec<String> = matches
+        .get_many::<String>("config")
+        .map(|files| files.cloned().collect())
+        .unwrap_or_default();
+    CONFIG_FILES.set(config_files).ok();
+
+    let arg_values = |name: &str| -> Vec<String> {
+        matches
+            .get_many::<String>(name)
+            .map(|values| values.cloned().collect())
+            .unwrap_or_default()
+    };
+    ENVIRONMENT
+        .set(load_environment(&arg_values("env-file"), &arg_values("env-allow")))
+        .ok();
 
     if let Some(matches) = matches.subcommand_matches("merge") {
         run_subcommand_merge(matches);
@@ -179,32 +381,68 @@ fn main() {
     if let Some(matches) = matches.subcommand_matches("execute") {
         run_subcommand_execute(matches);
     }
+
+    if let Some(matches) = matches.subcommand_matches("query") {
+        run_subcommand_query(matches);
+    }
+
+    if let Some(matches) = matches.subcommand_matches("functions") {
+        run_subcommand_functions(matches);
+    }
 }
 
 fn run_subcommand_merge(matches: &clap::ArgMatches) {
     let global_args = GlobalArguments::from_matches(matches);
-    let input1_paths: &String = matches.get_one::<String>("input1").unwrap();
-    let input2_paths: &String = matches.get_one::<String>("input2").unwrap();
+    let input_paths: Vec<&String> = matches.get_many::<String>("input").unwrap().collect();
     let output_path = matches.get_one::<String>("output").unwrap();
 
-    let mut merged_yaml = Value::Null;
+    let directory_options = DirectoryOptions::from_matches(matches);
+    let merge_options = MergeOptions::from_matches(matches);
+
+    let provenance_format = matches.get_one::<String>("provenance");
+    let conflicts_output = matches.get_one::<String>("conflicts");
+    let strict = matches.get_flag("strict");
+    let strict_vars = matches.get_flag("strict-vars");
+    let mut tracker = MergeTracker {
+        enabled: provenance_format.is_some() || conflicts_output.is_some() || strict || strict_vars,
+        ..Default::default()
+    };
+    for protect_path in matches.get_many::<String>("protect").unwrap_or_default() {
+        tracker.add_protected(protect_path, "--protect");
+    }
 
-    let mut input_paths: Vec<&str> = Vec::new();
-    input_paths.push(input1_paths);
-    input_paths.push(input2_paths);
+    let mut merged_yaml = Value::Null;
 
     for input_path in input_paths {
-        let path = Path::new(input_path);
-
-        if path.exists() == false {
-            print_error!("File does not exist: {}", input_path);
-            std::process::exit(1);
+        for file in resolve_input_path(input_path, &directory_options) {
+            merge_yaml_file(&file, &mut merged_yaml, &merge_options, &mut tracker);
         }
+    }
 
-        if path.is_dir() {
-            print_error!("Directory not supported yet: {}", input_path);
+    if let Some(conflicts_output) = conflicts_output {
+        if conflicts_output.is_empty() {
+            for conflict in &tracker.conflicts {
+                print_warning!("{}", conflict);
+            }
         } else {
-            merge_yaml_file(path, &mut merged_yaml);
+            let conflicts: Vec<Value> = tracker.conflicts.iter().map(MergeConflict::to_value).collect();
+            save_to_file(Path::new(conflicts_output), &serde_yaml::to_string(&conflicts).unwrap());
+            print_info!("{} conflicts written to {}", conflicts.len(), conflicts_output);
+        }
+    }
+
+    if strict {
+        let violations: Vec<&MergeConflict> = tracker
+            .conflicts
+            .iter()
+            .filter(|conflict| conflict.is_strict_violation())
+            .collect();
+        if !violations.is_empty() {
+            for conflict in &violations {
+                print_error!("{}", conflict);
+            }
+            print_error!("Strict merge failed with {} conflicts", violations.len());
+            std::process::exit(1);
         }
     }
 
@@ -218,25 +456,163 @@ fn run_subcommand_merge(matches: &clap::ArgMatches) {
         print_error!("version not found");
     }
 
-    let output_yaml = serde_yaml::to_string(&merged_yaml).unwrap();
+    let macros = take_macros(&mut merged_yaml);
+    let mut template_context = TemplateContext::new(&merged_yaml);
+    register_macros(&mut template_context, matches, macros);
+    // texts with literal braces are written as !raw, so the output can be merged again or run by
+    // execute with the braces as they are
+    let output_yaml = template_context
+        .resolve_value(&merged_yaml, "")
+        .map(|resolved| tag_literal_braces(&resolved))
+        .unwrap_or_else(|e| {
+            print_error!("{}", e);
+            std::process::exit(1);
+        });
 
-    let mut output_yaml_string = output_yaml.to_string();
-    while output_yaml_string.contains("{{") {
-        output_yaml_string = replace_placeholders(&output_yaml_string, &merged_yaml);
+    // unknown paths are written as empty strings, unless --strict-vars
+    for unresolved in &template_context.unresolved {
+        let location = match tracker.location_of(&unresolved.path) {
+            Some(location) => format!(" ({})", location),
+            None => "".to_string(),
+        };
+        let message = format!(
+            "Unresolved reference '{}' in '{}'{}",
+            unresolved.reference, unresolved.path, location
+        );
+        if strict_vars {
+            print_error!("{}", message);
+        } else {
+            print_warning!("{}, written as an empty string", message);
+        }
     }
-
-    if output_yaml_string.contains("{{") {
+    if strict_vars && !template_context.unresolved.is_empty() {
         print_error!(
-            "Output path contains unresolved variables: {}",
-            output_yaml_string
+            "Strict merge failed with {} unresolved references",
+            template_context.unresolved.len()
         );
         std::process::exit(1);
     }
 
+    let mut output_yaml_string = serde_yaml::to_string(&output_yaml).unwrap();
+
+    if let Some(provenance_format) = provenance_format {
+        let provenance = tracker.provenance(&merged_yaml);
+        match provenance_format.as_str() {
+            "comments" => {
+                output_yaml_string.push_str("\n# provenance\n");
+                for (path, location) in provenance {
+                    output_yaml_string.push_str(&format!("# {}: {}\n", path, location));
+                }
+            }
+            format => {
+                let provenance: serde_yaml::Mapping = provenance
+                    .into_iter()
+                    .map(|(path, location)| (Value::String(path), location.to_value()))
+                    .collect();
+                let default_path = format!("{}.provenance.{}", output_path, format);
+                let provenance_path = matches
+                    .get_one::<String>("provenance-output")
+                    .unwrap_or(&default_path);
+                let provenance_string = if format == "json" {
+                    serde_json::to_string_pretty(&provenance).unwrap()
+                } else {
+                    serde_yaml::to_string(&provenance).unwrap()
+                };
+                save_to_file(Path::new(provenance_path), &provenance_string);
+                print_info!("Provenance written to {}", provenance_path);
+            }
+        }
+    }
+
     fs::write(output_path, output_yaml_string).unwrap();
     global_args.display_summary();
 }
 
+fn run_subcommand_query(matches: &clap::ArgMatches) {
+    let path = matches.get_one::<String>("path").unwrap();
+    let format = matches.get_one::<String>("format").unwrap();
+
+    let mut merged_yaml = Value::Null;
+    for input_path in matches.get_many::<String>("input").unwrap() {
+        for file in resolve_input_path(input_path, &DirectoryOptions::default()) {
+            merge_yaml_file(&file, &mut merged_yaml, &MergeOptions::default(), &mut MergeTracker::default());
+        }
+    }
+
+    let macros = take_macros(&mut merged_yaml);
+    let mut template_context = TemplateContext::new(&merged_yaml);
+    register_macros(&mut template_context, matches, macros);
+    let resolved_yaml = template_context
+        .resolve_value(&merged_yaml, "")
+        .unwrap_or_else(|e| {
+            print_error!("{}", e);
+            std::process::exit(1);
+        });
+
+    let value = match query_nested_value(&resolved_yaml, path) {
+        Ok(Some(value)) => value,
+        Ok(None) => {
+            print_error!("Path not found: {}", path);
+            std::process::exit(1);
+        }
+        Err(e) => {
+            print_error!("{}", e);
+            std::process::exit(1);
+        }
+    };
+
+    if format == "json" {
+        println!("{}", serde_json::to_string_pretty(&value).unwrap());
+    } else {
+        print!("{}", serde_yaml::to_string(&value).unwrap());
+    }
+}
+
+fn take_macros(merged_yaml: &mut Value) -> Option<Value> {
+    merged_yaml.as_mapping_mut()?.shift_remove(MACROS_KEY)
+}
+
+// the macros of the --macros files, then of the _macros: section of the data
+fn register_macros(context: &mut TemplateContext, matches: &clap::ArgMatches, section: Option<Value>) {
+    for macros_path in matches.get_many::<String>("macros").unwrap_or_default() {
+        let definitions: Value = fs::read_to_string(macros_path)
+            .map_err(|e| e.to_string())
+            .and_then(|content| serde_yaml::from_str(&content).map_err(|e| e.to_string()))
+            .unwrap_or_else(|e| {
+                print_error!("Failed to read macros file {}: {}", macros_path, e);
+                std::process::exit(1);
+            });
+        // the file holds the macros, or a _macros: section
+        let definitions = definitions.get(MACROS_KEY).cloned().unwrap_or(definitions);
+        if let Err(e) = context.register_macros(&definitions) {
+            print_error!("{} in {}", e, macros_path);
+            std::process::exit(1);
+        }
+    }
+
+    if let Some(section) = section {
+        if let Err(e) = context.register_macros(&section) {
+            print_error!("{} in the {} section", e, MACROS_KEY);
+            std::process::exit(1);
+        }
+    }
+}
+
+fn run_subcommand_functions(matches: &clap::ArgMatches) {
+    let mut template_context = TemplateContext::new(&Value::Null);
+    register_macros(&mut template_context, matches, None);
+    let registry = &template_context.functions;
+    let signatures: Vec<String> = registry
+        .functions
+        .iter()
+        .map(|function| FunctionRegistry::signature(function.as_ref()))
+        .collect();
+    let width = signatures.iter().map(String::len).max().unwrap_or(0);
+    for (function, signature) in registry.functions.iter().zip(signatures) {
+        println!("{:width$}  {}", signature, function.description(), width = width);
+    }
+}
+
 fn run_subcommand_execute(matches: &clap::ArgMatches) {
     let global_args = GlobalArguments::from_matches(matches);
     let input_path: &String = matches.get_one::<String>("input1").unwrap();
@@ -248,12 +624,17 @@ fn run_subcommand_execute(matches: &clap::ArgMatches) {
     let path_in = Path::new(input_path);
     let path_out = Path::new(output_path);
 
-    if path_in.exists() == false {
+    if !path_in.exists() {
         print_error!("File does not exist: {}", input_path);
         std::process::exit(1);
     }
 
-    merge_yaml_file(path_in, &mut yaml);
+    merge_yaml_file(
+        path_in,
+        &mut yaml,
+        &MergeOptions::default(),
+        &mut MergeTracker::default(),
+    );
 
     //set_nested_value(&mut output_yaml, "execution.date", Value::String("{{get_date()}}".to_string()));
 
@@ -294,15 +675,15 @@ impl GlobalArguments {
         //DEBUG: print_debug!("Global debug flag: {:?}", global_debug_flag);
         let global_verbose_level: String = matches.get_one::<String>("verbose").unwrap().clone();
         //DEBUG: print_debug!("Global verbose level: {:?}", global_verbose_level);
-        return GlobalArguments {
+        GlobalArguments {
             verbose: global_verbose_level,
             debug: global_debug_flag,
-        };
+        }
     }
 
     fn display_summary(&self) {
         if self.debug {
-            println!("");
+            println!();
             print_banner_yellow!("### Global Arguments #####################################");
             print_warning!("Debug: {}; Verbose: {};", self.debug, self.verbose);
             print_banner_yellow!("##########################################################");
@@ -310,6 +691,46 @@ impl GlobalArguments {
     }
 }
 
+#[derive(Default)]
+struct DirectoryOptions {
+    recursive: bool,
+    include: Vec<glob::Pattern>,
+    exclude: Vec<glob::Pattern>,
+}
+
+impl DirectoryOptions {
+    fn from_matches(matches: &clap::ArgMatches) -> Self {
+        DirectoryOptions {
+            recursive: matches.get_flag("recursive"),
+            include: parse_glob_patterns(matches, "include"),
+            exclude: parse_glob_patterns(matches, "exclude"),
+        }
+    }
+
+    fn accepts(&self, relative_path: &str) -> bool {
+        // without include patterns only the yaml extensions are taken
+        let included = if self.include.is_empty() {
+            relative_path.ends_with(".yaml") || relative_path.ends_with(".yml")
+        } else {
+            self.include.iter().any(|p| p.matches(relative_path))
+        };
+        included && !self.exclude.iter().any(|p| p.matches(relative_path))
+    }
+}
+
+fn parse_glob_patterns(matches: &clap::ArgMatches, name: &str) -> Vec<glob::Pattern> {
+    matches
+        .get_many::<String>(name)
+        .unwrap_or_default()
+        .map(|pattern| {
+            glob::Pattern::new(pattern).unwrap_or_else(|e| {
+                print_error!("Invalid --{} pattern '{}': {}", name, pattern, e);
+                std::process::exit(1);
+            })
+        })
+        .collect()
+}
+
 struct Counters {
     total: i32,
     executed: i32,
@@ -319,7 +740,7 @@ struct Counters {
 
 impl Counters {
     fn display_summary(&self) {
-        println!("");
+        println!();
         print_banner_blue!("### Summary ##############################################");
         print_info!(
             "Total: {}; Executed: {}; Skipped: {}; Errors: {}",
@@ -348,41 +769,36 @@ impl OSTask {
         let default_output: String = format!("outs.output_{}", command_index);
         let os_task = OSTask {
             name: task
-                .get(&Value::String("name".to_string()))
+                .get(Value::String("name".to_string()))
                 .unwrap_or(&Value::Null)
                 .as_str()
                 .unwrap_or(&default_name)
                 .to_string(),
             description: task
-                .get(&Value::String("description".to_string()))
+                .get(Value::String("description".to_string()))
                 .unwrap_or(&Value::Null)
                 .as_str()
                 .unwrap_or("None")
                 .to_string(),
             execute: task
-                .get(&Value::String("execute".to_string()))
+                .get(Value::String("execute".to_string()))
                 .unwrap_or(&Value::Null)
                 .as_bool()
                 .unwrap_or(true),
             debug_flag: task
-                .get(&Value::String("debug".to_string()))
+                .get(Value::String("debug".to_string()))
                 .unwrap_or(&Value::Null)
                 .as_bool()
                 .unwrap_or(false),
-            cmd: task
-                .get(&Value::String("cmd".to_string()))
-                .unwrap()
-                .as_str()
-                .unwrap()
-                .to_string(),
+            cmd: template_source(task.get(Value::String("cmd".to_string())).unwrap()).unwrap(),
             output: task
-                .get(&Value::String("output".to_string()))
+                .get(Value::String("output".to_string()))
                 .unwrap_or(&Value::Null)
                 .as_str()
                 .unwrap_or(&default_output)
                 .to_string(),
         };
-        return os_task;
+        os_task
     }
     fn display_message(&self) {
         let display_msg = format!("{}: {}", self.name, self.description);
@@ -406,41 +822,36 @@ impl ConsoleTask {
         let default_output: String = format!("outs.output_{}", command_index);
         let console_task = ConsoleTask {
             name: task
-                .get(&Value::String("name".to_string()))
+                .get(Value::String("name".to_string()))
                 .unwrap_or(&Value::Null)
                 .as_str()
                 .unwrap_or(&default_name)
                 .to_string(),
             description: task
-                .get(&Value::String("description".to_string()))
+                .get(Value::String("description".to_string()))
                 .unwrap_or(&Value::Null)
                 .as_str()
                 .unwrap_or("None")
                 .to_string(),
             execute: task
-                .get(&Value::String("execute".to_string()))
+                .get(Value::String("execute".to_string()))
                 .unwrap_or(&Value::Null)
                 .as_bool()
                 .unwrap_or(true),
             debug_flag: task
-                .get(&Value::String("debug".to_string()))
+                .get(Value::String("debug".to_string()))
                 .unwrap_or(&Value::Null)
                 .as_bool()
                 .unwrap_or(false),
-            message: task
-                .get(&Value::String("message".to_string()))
-                .unwrap()
-                .as_str()
-                .unwrap()
-                .to_string(),
+            message: template_source(task.get(Value::String("message".to_string())).unwrap()).unwrap(),
             output: task
-                .get(&Value::String("output".to_string()))
+                .get(Value::String("output".to_string()))
                 .unwrap_or(&Value::Null)
                 .as_str()
                 .unwrap_or(&default_output)
                 .to_string(),
         };
-        return console_task;
+        console_task
     }
 
     fn display_message(&self) {
@@ -465,41 +876,41 @@ impl HTTPTask {
         let default_output: String = format!("outs.output_{}", command_index);
         let http_task = HTTPTask {
             name: task
-                .get(&Value::String("name".to_string()))
+                .get(Value::String("name".to_string()))
                 .unwrap_or(&Value::Null)
                 .as_str()
                 .unwrap_or(&default_name)
                 .to_string(),
             description: task
-                .get(&Value::String("description".to_string()))
+                .get(Value::String("description".to_string()))
                 .unwrap_or(&Value::Null)
                 .as_str()
                 .unwrap_or("None")
                 .to_string(),
             execute: task
-                .get(&Value::String("execute".to_string()))
+                .get(Value::String("execute".to_string()))
                 .unwrap_or(&Value::Null)
                 .as_bool()
                 .unwrap_or(true),
             debug_flag: task
-                .get(&Value::String("debug".to_string()))
+                .get(Value::String("debug".to_string()))
                 .unwrap_or(&Value::Null)
                 .as_bool()
                 .unwrap_or(false),
             url: task
-                .get(&Value::String("url".to_string()))
+                .get(Value::String("url".to_string()))
                 .unwrap()
                 .as_str()
                 .unwrap()
                 .to_string(),
             output: task
-                .get(&Value::String("output".to_string()))
+                .get(Value::String("output".to_string()))
                 .unwrap_or(&Value::Null)
                 .as_str()
                 .unwrap_or(&default_output)
                 .to_string(),
         };
-        return http_task;
+        http_task
     }
     fn display_message(&self) {
         let display_msg = format!("{}: {}", self.name, self.description);
@@ -512,7 +923,7 @@ struct AzureAuthentication {
     auth_type: String,
     tenant: String,
     client: String,
-    secret: String
+    secret: String,
 }
 
 impl AzureAuthentication {
@@ -525,35 +936,41 @@ impl AzureAuthentication {
 
         let auth: AzureAuthentication = AzureAuthentication {
             auth_type: task
-                .get(&Value::String("auth_type".to_string()))
+                .get(Value::String("auth_type".to_string()))
                 .unwrap_or(&Value::Null)
                 .as_str()
                 .unwrap_or("None")
                 .to_string(),
             tenant: task
-                .get(&Value::String("tenant".to_string()))
+                .get(Value::String("tenant".to_string()))
                 .unwrap_or(&Value::Null)
                 .as_str()
                 .unwrap_or(&default_tenant)
                 .to_string(),
             client: task
-                .get(&Value::String("client".to_string()))
+                .get(Value::String("client".to_string()))
                 .unwrap_or(&Value::Null)
                 .as_str()
                 .unwrap_or(&default_client)
                 .to_string(),
             secret: task
-                .get(&Value::String("secret".to_string()))
+                .get(Value::String("secret".to_string()))
                 .unwrap_or(&Value::Null)
                 .as_str()
                 .unwrap_or(&default_secret)
                 .to_string(),
         };
-        return auth;
+        auth
     }
     
+    // the message never shows the secret, only whether one is set
+    fn display_auth(&self) -> String {
+        let secret = if self.secret == "None" { "no secret" } else { "secret set" };
+        format!("{} {} {} ({})", self.auth_type, self.tenant, self.client, secret)
+    }
+
     fn debug_message(&self) {
-        let display_msg = format!("{} {} {}", self.auth_type, self.tenant, self.client);
+        let display_msg = self.display_auth();
         print_executing!("{}", display_msg);
     }
 }
@@ -565,7 +982,7 @@ struct AzureTask {
     execute: bool,
     debug_flag: bool,
     auth: AzureAuthentication,
-    output: String
+    output: String,
 }
 
 impl AzureTask {
@@ -573,40 +990,40 @@ impl AzureTask {
         let default_name: String = format!("Command Index {}", command_index);
         let default_output: String = format!("outs.output_{}", command_index);
 
-        let auth = AzureAuthentication::from_value(task.get(&Value::String("auth".to_string())).unwrap());
+        let auth = AzureAuthentication::from_value(task.get(Value::String("auth".to_string())).unwrap());
 
         let azure_task = AzureTask {
             name: task
-                .get(&Value::String("name".to_string()))
+                .get(Value::String("name".to_string()))
                 .unwrap_or(&Value::Null)
                 .as_str()
                 .unwrap_or(&default_name)
                 .to_string(),
             description: task
-                .get(&Value::String("description".to_string()))
+                .get(Value::String("description".to_string()))
                 .unwrap_or(&Value::Null)
                 .as_str()
                 .unwrap_or("None")
                 .to_string(),
             execute: task
-                .get(&Value::String("execute".to_string()))
+                .get(Value::String("execute".to_string()))
                 .unwrap_or(&Value::Null)
                 .as_bool()
                 .unwrap_or(true),
             debug_flag: task
-                .get(&Value::String("debug".to_string()))
+                .get(Value::String("debug".to_string()))
                 .unwrap_or(&Value::Null)
                 .as_bool()
                 .unwrap_or(false),
-            auth: auth,
+            auth,
             output: task
-                .get(&Value::String("output".to_string()))
+                .get(Value::String("output".to_string()))
                 .unwrap_or(&Value::Null)
                 .as_str()
                 .unwrap_or(&default_output)
                 .to_string(),
         };
-        return azure_task;
+        azure_task
     }
     fn display_message(&self) {
         let display_msg = format!("{}: {}", self.name, self.description);
@@ -633,27 +1050,27 @@ fn run_command_task(
     // loop.for: start: 1 end: 10 cmd: 'echo {{get_date()}}'
 
     // in case task key is eq to "task" print a task, in case of "loop" print a loop command
-    if let Some(task) = task.get(&Value::String("os.win.cmd".to_string())) {
+    if let Some(task) = task.get(Value::String("os.win.cmd".to_string())) {
         let os_task: OSTask = OSTask::from_value(task, command_index);
         run_task_os_win_cmd(&os_task, counter, output_yaml);
-    } else if let Some(task) = task.get(&Value::String("os.linux.cmd".to_string())) {
+    } else if let Some(task) = task.get(Value::String("os.linux.cmd".to_string())) {
         let os_task: OSTask = OSTask::from_value(task, command_index);
         run_task_os_linux_cmd(&os_task, counter, output_yaml);
-    } else if let Some(task) = task.get(&Value::String("os.win.ps".to_string())) {
+    } else if let Some(task) = task.get(Value::String("os.win.ps".to_string())) {
         let os_task: OSTask = OSTask::from_value(task, command_index);
         run_task_os_win_ps(&os_task, counter, output_yaml);
-    } else if let Some(task) = task.get(&Value::String("os.cmd".to_string())) {
+    } else if let Some(task) = task.get(Value::String("os.cmd".to_string())) {
         let os_task: OSTask = OSTask::from_value(task, command_index);
         run_task_os_cmd(&os_task, counter, output_yaml);
-    } else if let Some(task) = task.get(&Value::String("console.print".to_string())) {
+    } else if let Some(task) = task.get(Value::String("console.print".to_string())) {
         let console_task: ConsoleTask = ConsoleTask::from_value(task, command_index);
         run_task_console_print(&console_task, counter, output_yaml);
-    } else if let Some(task) = task.get(&Value::String("loop.for".to_string())) {
+    } else if let Some(task) = task.get(Value::String("loop.for".to_string())) {
         run_command_loop(command_index, task, counter, output_yaml);
-    } else if let Some(task) = task.get(&Value::String("http.get".to_string())) {
+    } else if let Some(task) = task.get(Value::String("http.get".to_string())) {
         let http_task = HTTPTask::from_value(task, command_index);
         run_task_http_get(&http_task, counter, output_yaml);
-    } else if let Some(task) = task.get(&Value::String("az.login".to_string())) {
+    } else if let Some(task) = task.get(Value::String("az.login".to_string())) {
         let azure_task = AzureTask::from_value(task, command_index);
         run_task_azure_login(&azure_task, counter, output_yaml);        
     } else {
@@ -668,28 +1085,8 @@ fn run_task_console_print(task: &ConsoleTask, counter: &mut Counters, output_yam
         counter.executed += 1;
         task.display_message();
 
-        let mut execute_command_output_value = Value::String(task.message.to_string());
-
-        // TODO: this needs to be refactored to a function
-
-        if let Some(execute_command_output_str) = execute_command_output_value.as_str() {
-            if execute_command_output_str.contains("{{") {
-                let mut output_yaml_string = task.message.to_string();
-                while output_yaml_string.contains("{{") {
-                    output_yaml_string = replace_placeholders(&output_yaml_string, output_yaml);
-                }
-
-                if output_yaml_string.contains("{{") {
-                    print_error!(
-                        "Output path contains unresolved variables: {}",
-                        output_yaml_string
-                    );
-                    std::process::exit(1);
-                }
-
-                execute_command_output_value = Value::String(output_yaml_string);
-            }
-        }
+        let message = render_task_text(&task.message, output_yaml);
+        let execute_command_output_value = Value::String(message);
 
         println!("{:?}", task.message.to_string());
 
@@ -697,9 +1094,9 @@ fn run_task_console_print(task: &ConsoleTask, counter: &mut Counters, output_yam
         let path2 = format!("{}.err", task.output);
         let path3 = format!("{}.sts", task.output);
 
-        set_nested_value(output_yaml, &path1.as_str(), execute_command_output_value);
-        set_nested_value(output_yaml, &path2.as_str(), Value::String("".to_string()));
-        set_nested_value(output_yaml, &path3.as_str(), Value::String("0".to_string()));
+        set_nested_value(output_yaml, path1.as_str(), execute_command_output_value);
+        set_nested_value(output_yaml, path2.as_str(), Value::String("".to_string()));
+        set_nested_value(output_yaml, path3.as_str(), Value::String("0".to_string()));
         if task.debug_flag {
             print_debug!("### End: {:?}", output_yaml);
         }
@@ -722,9 +1119,10 @@ fn run_task_os_win_cmd(task: &OSTask, counter: &mut Counters, output_yaml: &mut
         }
 
         // execute the command
+        let cmd = render_task_text(&task.cmd, output_yaml);
         let execute_command_output = std::process::Command::new("cmd")
             .arg("/c")
-            .arg(task.cmd.clone())
+            .arg(&cmd)
             .output()
             .unwrap();
 
@@ -733,7 +1131,7 @@ fn run_task_os_win_cmd(task: &OSTask, counter: &mut Counters, output_yaml: &mut
         let execute_command_output_error =
             Value::String(String::from_utf8_lossy(&execute_command_output.stderr).to_string());
         let execute_command_output_status =
-            Value::String((&execute_command_output.status).to_string());
+            Value::String(execute_command_output.status.to_string());
 
         let path1 = format!("{}.out", task.output);
         let path2 = format!("{}.err", task.output);
@@ -741,9 +1139,9 @@ fn run_task_os_win_cmd(task: &OSTask, counter: &mut Counters, output_yaml: &mut
 
         print_output!("{:?}", execute_command_output_value);
 
-        set_nested_value(output_yaml, &path1.as_str(), execute_command_output_value);
-        set_nested_value(output_yaml, &path2.as_str(), execute_command_output_error);
-        set_nested_value(output_yaml, &path3.as_str(), execute_command_output_status);
+        set_nested_value(output_yaml, path1.as_str(), execute_command_output_value);
+        set_nested_value(output_yaml, path2.as_str(), execute_command_output_error);
+        set_nested_value(output_yaml, path3.as_str(), execute_command_output_status);
         if task.debug_flag {
             print_debug!("### End: {:?}", output_yaml);
         }
@@ -766,9 +1164,10 @@ fn run_task_os_linux_cmd(task: &OSTask, counter: &mut Counters, output_yaml: &mu
         }
 
         // execute the command
+        let cmd = render_task_text(&task.cmd, output_yaml);
         let execute_command_output = std::process::Command::new("sh")
             .arg("-c")
-            .arg(task.cmd.clone())
+            .arg(&cmd)
             .output()
             .unwrap();
 
@@ -777,7 +1176,7 @@ fn run_task_os_linux_cmd(task: &OSTask, counter: &mut Counters, output_yaml: &mu
         let execute_command_output_error =
             Value::String(String::from_utf8_lossy(&execute_command_output.stderr).to_string());
         let execute_command_output_status =
-            Value::String((&execute_command_output.status).to_string());
+            Value::String(execute_command_output.status.to_string());
 
         let path1 = format!("{}.out", task.output);
         let path2 = format!("{}.err", task.output);
@@ -785,9 +1184,9 @@ fn run_task_os_linux_cmd(task: &OSTask, counter: &mut Counters, output_yaml: &mu
 
         print_output!("{:?}", execute_command_output_value);
 
-        set_nested_value(output_yaml, &path1.as_str(), execute_command_output_value);
-        set_nested_value(output_yaml, &path2.as_str(), execute_command_output_error);
-        set_nested_value(output_yaml, &path3.as_str(), execute_command_output_status);
+        set_nested_value(output_yaml, path1.as_str(), execute_command_output_value);
+        set_nested_value(output_yaml, path2.as_str(), execute_command_output_error);
+        set_nested_value(output_yaml, path3.as_str(), execute_command_output_status);
         if task.debug_flag {
             print_debug!("### End: {:?}", output_yaml);
         }
@@ -810,9 +1209,10 @@ fn run_task_os_win_ps(task: &OSTask, counter: &mut Counters, output_yaml: &mut V
         }
 
         // execute the command
+        let cmd = render_task_text(&task.cmd, output_yaml);
         let execute_command_output = std::process::Command::new("powershell")
             .arg("-Command")
-            .arg(task.cmd.clone())
+            .arg(&cmd)
             .output()
             .unwrap();
 
@@ -821,7 +1221,7 @@ fn run_task_os_win_ps(task: &OSTask, counter: &mut Counters, output_yaml: &mut V
         let execute_command_output_error =
             Value::String(String::from_utf8_lossy(&execute_command_output.stderr).to_string());
         let execute_command_output_status =
-            Value::String((&execute_command_output.status).to_string());
+            Value::String(execute_command_output.status.to_string());
 
         let path1 = format!("{}.out", task.output);
         let path2 = format!("{}.err", task.output);
@@ -829,9 +1229,9 @@ fn run_task_os_win_ps(task: &OSTask, counter: &mut Counters, output_yaml: &mut V
 
         print_output!("{:?}", execute_command_output_value);
 
-        set_nested_value(output_yaml, &path1.as_str(), execute_command_output_value);
-        set_nested_value(output_yaml, &path2.as_str(), execute_command_output_error);
-        set_nested_value(output_yaml, &path3.as_str(), execute_command_output_status);
+        set_nested_value(output_yaml, path1.as_str(), execute_command_output_value);
+        set_nested_value(output_yaml, path2.as_str(), execute_command_output_error);
+        set_nested_value(output_yaml, path3.as_str(), execute_command_output_status);
         if task.debug_flag {
             print_debug!("### End: {:?}", output_yaml);
         }
@@ -849,26 +1249,26 @@ fn run_task_os_cmd(task: &OSTask, counter: &mut Counters, output_yaml: &mut Valu
         task.display_message();
 
         // execute the command
-        let execute_command_output: std::process::Output;
-        if cfg!(target_os = "windows") {
-            execute_command_output = std::process::Command::new("cmd")
+        let cmd = render_task_text(&task.cmd, output_yaml);
+        let execute_command_output: std::process::Output = if cfg!(target_os = "windows") {
+            std::process::Command::new("cmd")
                 .arg("/c")
-                .arg(task.cmd.clone())
+                .arg(&cmd)
                 .output()
-                .unwrap();
+                .unwrap()
         } else {
-            execute_command_output = std::process::Command::new("sh")
+            std::process::Command::new("sh")
                 .arg("-c")
-                .arg(task.cmd.clone())
+                .arg(&cmd)
                 .output()
-                .unwrap();
-        }
+                .unwrap()
+        };
         let execute_command_output_value =
             Value::String(String::from_utf8_lossy(&execute_command_output.stdout).to_string());
         let execute_command_output_error =
             Value::String(String::from_utf8_lossy(&execute_command_output.stderr).to_string());
         let execute_command_output_status =
-            Value::String((&execute_command_output.status).to_string());
+            Value::String(execute_command_output.status.to_string());
 
         let path1 = format!("{}.out", task.output);
         let path2 = format!("{}.err", task.output);
@@ -876,9 +1276,9 @@ fn run_task_os_cmd(task: &OSTask, counter: &mut Counters, output_yaml: &mut Valu
 
         print_output!("{:?}", execute_command_output_value);
 
-        set_nested_value(output_yaml, &path1.as_str(), execute_command_output_value);
-        set_nested_value(output_yaml, &path2.as_str(), execute_command_output_error);
-        set_nested_value(output_yaml, &path3.as_str(), execute_command_output_status);
+        set_nested_value(output_yaml, path1.as_str(), execute_command_output_value);
+        set_nested_value(output_yaml, path2.as_str(), execute_command_output_error);
+        set_nested_value(output_yaml, path3.as_str(), execute_command_output_status);
         if task.debug_flag {
             print_debug!("### End: {:?}", output_yaml);
         }
@@ -906,8 +1306,8 @@ fn run_task_http_get(task: &HTTPTask, counter: &mut Counters, output_yaml: &mut
 
             print_output!("{:?}", response_text);
 
-            set_nested_value(output_yaml, &path1.as_str(), Value::String(response_text));
-            set_nested_value(output_yaml, &path2.as_str(), Value::String(response_status));
+            set_nested_value(output_yaml, path1.as_str(), Value::String(response_text));
+            set_nested_value(output_yaml, path2.as_str(), Value::String(response_status));
 
             if task.debug_flag {
                 print_debug!("### End: {:?}", output_yaml);
@@ -931,6 +1331,15 @@ fn run_task_azure_login(task: &AzureTask, counter: &mut Counters, output_yaml: &
 
         task.auth.debug_message();
 
+        // the login is only displayed for now, its outputs are written like the console ones
+        let path1 = format!("{}.out", task.output);
+        let path2 = format!("{}.err", task.output);
+        let path3 = format!("{}.sts", task.output);
+
+        set_nested_value(output_yaml, path1.as_str(), Value::String(task.auth.display_auth()));
+        set_nested_value(output_yaml, path2.as_str(), Value::String("".to_string()));
+        set_nested_value(output_yaml, path3.as_str(), Value::String("0".to_string()));
+
         if task.debug_flag {
             print_debug!("### End: {:?}", output_yaml);
         }
@@ -952,12 +1361,12 @@ fn run_command_loop(
 
     counter.total += 1;
     let execute: bool = task
-        .get(&Value::String("execute".to_string()))
+        .get(Value::String("execute".to_string()))
         .unwrap_or(&Value::Null)
         .as_bool()
         .unwrap_or(true);
     let debug_flag: bool = task
-        .get(&Value::String("debug".to_string()))
+        .get(Value::String("debug".to_string()))
         .unwrap_or(&Value::Null)
         .as_bool()
         .unwrap_or(false);
@@ -967,28 +1376,28 @@ fn run_command_loop(
         let default_name: String = format!("Command Index {}", command_index);
         //let default_output: String = format!("outs.output_{}", command_index);
         let name = task
-            .get(&Value::String("name".to_string()))
+            .get(Value::String("name".to_string()))
             .unwrap_or(&Value::Null)
             .as_str()
             .unwrap_or(&default_name);
         let description = task
-            .get(&Value::String("description".to_string()))
+            .get(Value::String("description".to_string()))
             .unwrap_or(&Value::Null)
             .as_str()
             .unwrap_or("None");
 
         let start = task
-            .get(&Value::String("start".to_string()))
+            .get(Value::String("start".to_string()))
             .unwrap()
             .as_i64()
             .unwrap();
         let end = task
-            .get(&Value::String("end".to_string()))
+            .get(Value::String("end".to_string()))
             .unwrap()
             .as_i64()
             .unwrap();
         let index_text = task
-            .get(&Value::String("index".to_string()))
+            .get(Value::String("index".to_string()))
             .unwrap_or(&Value::Null)
             .as_str()
             .unwrap_or("index");
@@ -998,7 +1407,7 @@ fn run_command_loop(
 
         for i in start..end {
             let loop_tasks = task
-                .get(&Value::String("tasks".to_string()))
+                .get(Value::String("tasks".to_string()))
                 .unwrap()
                 .as_sequence()
                 .unwrap();
@@ -1007,7 +1416,7 @@ fn run_command_loop(
                 print_debug!("### Tasks: {:?}", loop_tasks);
             }
             for loop_task in loop_tasks.iter() {
-                let index_text = format!("{}", index_text);
+                let index_text = index_text.to_string();
                 let index_value = Value::String(i.to_string());
                 set_nested_value(output_yaml, &index_text, index_value);
 
@@ -1020,305 +1429,3826 @@ fn run_command_loop(
     }
 }
 
-fn replace_placeholders(output_yaml: &str, merged_yaml: &Value) -> String {
-    let re = regex::Regex::new(r"\{\{([^{}]*)\}\}").unwrap();
-    re.replace_all(output_yaml, |caps: &regex::Captures| {
-        let key = caps.get(1).unwrap().as_str().trim();
-        //DEBUG: println!("Key: {:?}", key);
+// upper bound of replacement passes over a text, a text still holding {{ after them
+// can never be resolved (e.g. a {{ without its }})
+const MAX_PLACEHOLDER_PASSES: usize = 10;
+
+// top level key of the macros, like _merge it is not part of the output
+const MACROS_KEY: &str = "_macros";
+
+// keys of the blocks resolved with the placeholders:
+//   sku:                                      accounts:
+//     $if: instance.stage == 'prod'             $for: item in azure.prefix
+//     $then: Premium                            $do:
+//     $else: Standard                             name: "{{ item.text }}-{{ loop.index }}"
+// without a branch for its condition a $if leaves its key (or list item) out, the items of
+// a $for inside a list take its place
+const IF_KEY: &str = "$if";
+const THEN_KEY: &str = "$then";
+const ELSE_KEY: &str = "$else";
+const FOR_KEY: &str = "$for";
+const DO_KEY: &str = "$do";
+
+fn is_block(map: &serde_yaml::Mapping) -> bool {
+    map.contains_key(IF_KEY) || map.contains_key(FOR_KEY)
+}
 
-        if key.contains("|") {
-            handle_pipe(key, merged_yaml)
-        } else if key.contains("(") && key.contains(")") {
-            apply_function(key, merged_yaml)
-        } else if let Some(nested_value) = get_nested_value(merged_yaml, key) {
-            //println!("Nested value: {:?}", nested_value);
-            nested_value.as_str().unwrap().to_string()
-        } else {
-            //println!("Nested value not found");
-            "".to_string()
-        }
-    })
-    .to_string()
+// a value tagged !raw is written as it is, without resolving its placeholders
+const RAW_TAG: &str = "raw";
+
+// braces in the text a placeholder writes ({{ '{{' }}, a !raw value) and \{{ are kept as private
+// use characters while a text is resolved, so no later pass takes them for a placeholder; the
+// resolved text has the braces back
+const ESCAPED_OPEN: &str = "\u{E000}";
+const ESCAPED_CLOSE: &str = "\u{E001}";
+
+fn escape_braces(text: &str) -> String {
+    text.replace("{{", ESCAPED_OPEN).replace("}}", ESCAPED_CLOSE)
 }
 
-fn get_nested_value<'a>(yaml_value: &'a Value, path: &str) -> Option<&'a Value> {
-    //DEBUG: println!("yaml_value: {:?}", yaml_value);
-    path.split('.')
-        .map(str::trim)
-        .try_fold(yaml_value, |current_value, key| current_value.get(key))
+fn unescape_braces(text: &str) -> String {
+    text.replace(ESCAPED_OPEN, "{{").replace(ESCAPED_CLOSE, "}}")
 }
 
-fn merge_yaml_file(path: &Path, merged_yaml: &mut Value) {
-    let file_content = fs::read_to_string(path).unwrap();
-    // if file_content contains multiple documents (---), we need to split them and merge them separately
-    if file_content.contains("---") {
-        let documents: Vec<&str> = file_content.split("---").collect();
-        for document in documents {
-            let yaml: Value = serde_yaml::from_str(document).unwrap();
-            merge_yaml(merged_yaml, &yaml);
-        }
-        return;
-    } else {
-        let yaml: Value = serde_yaml::from_str(&file_content).unwrap();
-        merge_yaml(merged_yaml, &yaml);
+fn raw_value(value: Value) -> Value {
+    Value::Tagged(Box::new(serde_yaml::value::TaggedValue {
+        tag: serde_yaml::value::Tag::new(RAW_TAG),
+        value,
+    }))
+}
+
+// a resolved value with every text holding literal braces tagged !raw (a value that already has
+// a tag keeps it)
+fn tag_literal_braces(value: &Value) -> Value {
+    match value {
+        Value::String(text) if text.contains("{{") => raw_value(value.clone()),
+        Value::Mapping(map) => Value::Mapping(
+            map.iter()
+                .map(|(key, value)| (key.clone(), tag_literal_braces(value)))
+                .collect(),
+        ),
+        Value::Sequence(seq) => Value::Sequence(seq.iter().map(tag_literal_braces).collect()),
+        _ => value.clone(),
     }
 }
 
-fn merge_yaml(base: &mut Value, other: &Value) {
-    match (base, other) {
-        (Value::Mapping(base_map), Value::Mapping(other_map)) => {
-            for (key, value) in other_map {
-                merge_yaml(base_map.entry(key.clone()).or_insert(Value::Null), value);
-            }
-        }
-        (Value::Sequence(base_seq), Value::Sequence(other_seq)) => {
-            for value in other_seq {
-                // if the value already exists in the base sequence, skip it
-                if base_seq.contains(value) {
-                    continue;
-                }
-                base_seq.push(value.clone());
-            }
-        }
-        (base, other) => {
-            *base = other.clone();
+// template source of a task text, the braces of a !raw text are escaped
+fn template_source(value: &Value) -> Option<String> {
+    match value {
+        Value::Tagged(tagged) if tagged.tag == RAW_TAG => {
+            tagged.value.as_str().map(|text| text.replace("{{", "\\{{"))
         }
+        _ => value.as_str().map(|text| text.to_string()),
     }
 }
 
-// TODO Features
-// - read a configuration file, as yaml.
-// - read secrets from a file that is secure in the filesystem
-// - load the configurations as a static structure
-// - read environment variables
-
-fn read_config_file() -> Value {
-    // check if the file exists
-    let path = Path::new("config.yaml");
-    if path.exists() == false {
-        eprintln!("File does not exist: {}", path.display());
-        std::process::exit(1);
-    }
-    let config_file = fs::read_to_string("config.yaml").unwrap();
-    let config: Value = serde_yaml::from_str(&config_file).unwrap();
-    //DEBUG: println!("Config file loaded with: {:?}", config);
-    return config;
+// text of a task resolved against the outputs of the previous tasks, the outputs are taken as
+// !raw, so their braces (e.g. of a docker --format) are literal
+fn render_task_text(text: &str, output_yaml: &Value) -> String {
+    let outputs: serde_yaml::Mapping = output_yaml
+        .as_mapping()
+        .into_iter()
+        .flatten()
+        .map(|(key, value)| (key.clone(), raw_value(value.clone())))
+        .collect();
+    // a text written before the braces had to be escaped (e.g. echo '{{.Names}}') is run as it is
+    TemplateContext::new(&Value::Mapping(outputs))
+        .resolve_text(text)
+        .unwrap_or_else(|e| {
+            print_warning!(
+                "{}, the text is used as it is; write literal braces as \\{{{{ or tag the text !raw",
+                e
+            );
+            text.to_string()
+        })
 }
 
-fn load_environment_variables() -> Value {
-    let env_vars_origin: Vec<(String, String)> = std::env::vars().collect();
-    // convert to a yaml Value, mapping key and value
+#[derive(Debug)]
+struct TemplateError {
+    message: String,
+    // dotted path of the value holding the placeholder
+    path: Option<String>,
+    // column of the function call that failed
+    column: Option<usize>,
+}
 
-    let mut env_vars = Value::Mapping(serde_yaml::Mapping::new());
-    for (key, value) in env_vars_origin {
-        env_vars
-            .as_mapping_mut()
-            .unwrap()
-            .insert(Value::String(key), Value::String(value));
+impl TemplateError {
+    fn new(message: impl Into<String>) -> Self {
+        TemplateError {
+            message: message.into(),
+            path: None,
+            column: None,
+        }
     }
-    //DEBUG: println!("env_vars: {:?}", env_vars);
 
-    return env_vars;
+    // the innermost call is where the error is, the calls around it keep its column
+    fn at_column(mut self, column: usize) -> Self {
+        self.column.get_or_insert(column);
+        self
+    }
 }
 
-fn apply_function(function_statement: &str, merged_yaml: &Value) -> String {
-    // a function_statement is a string that contains a function with parameters
-    // it can be a function with paramenters (e.g. get_env('ALLUSERSPROFILE') or get_data('2021-01-01', '2021-01-31'))
-    // or a function without parameters (e.g. get_date())
-    // or a function with a value key (e.g. get_value(root.level1.name))
-
-    if function_statement == "" {
-        return "".to_string();
+impl std::fmt::Display for TemplateError {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        write!(f, "{}", self.message)?;
+        if let Some(column) = self.column {
+            write!(f, " (column {})", column)?;
+        }
+        match &self.path {
+            Some(path) if !path.is_empty() => write!(f, " (at '{}')", path),
+            _ => Ok(()),
+        }
     }
+}
 
-    if function_statement.contains("(") && function_statement.contains(")") {
-        // it is a function
-        let parts: Vec<&str> = function_statement.split("(").collect();
-        let function_name = parts[0].trim();
-        let params: Vec<&str> = parts[1].split(")").collect();
-        let params: Vec<&str> = params[0].split(",").collect();
-        let params: Vec<&str> = params.iter().map(|x| x.trim()).collect();
-
-        //DEBUG: println!("apply_function :: function_statement: {}", function_statement);
-
-        // Create a new vector to store the modified parameters
-        // in reality this needs to be a vector of Value, but for now it is a vector of String
-        let mut modified_params: Vec<String> = Vec::new();
-
-        // params without quotes, like get_value(root.level1.name, 'demo_value'), need to get the value of the key
-        // if the key is not found, return the default value
-        if params != [""] {
-            for param in params.iter() {
-                if !param.contains("'") {
-                    //DEBUG:
-                    //DEBUG: println!("param: {}", param);
-
-                    let key = param.trim_matches('\'');
-                    let value = get_nested_value(&merged_yaml, key).unwrap();
-                    if value == &Value::Null {
-                        // if the value is not found, return the default value
-                        modified_params.push("default".to_string());
-                    } else {
-                        modified_params.push(value.as_str().unwrap().to_string());
-                    }
-                } else {
-                    modified_params.push(param.trim_matches('\'').to_string());
-                }
-            }
+// resolves the placeholders of a document, a referenced value has its own placeholders
+// resolved first, so the chain of references is followed and a cycle is detected
+struct TemplateContext<'a> {
+    data: &'a Value,
+    // resolved leaf values, by dotted path
+    resolved: HashMap<String, Value>,
+    // paths being resolved, the chain reported on a cycle
+    resolving: Vec<String>,
+    // references to paths that are not in the data
+    unresolved: Vec<UnresolvedReference>,
+    functions: FunctionRegistry,
+    // variables of the macros being called (their parameters), innermost last
+    scopes: Vec<HashMap<String, Value>>,
+    // macros being called, the chain reported on a cycle
+    macro_calls: Vec<String>,
+    // data files of lookup, loaded once by path
+    data_files: HashMap<String, Value>,
+}
 
-            // functions with parameters
+#[derive(Debug, Clone, PartialEq)]
+struct UnresolvedReference {
+    reference: String,
+    // path of the value holding the placeholder
+    path: String,
+}
 
-            // function with 1 parameter can be used as filters in a pipe
-            if function_name == "upper" {
-                let func_param_1 = modified_params.index(0);
-                return func_param_1.to_uppercase();
-            }
+impl<'a> TemplateContext<'a> {
+    fn new(data: &'a Value) -> Self {
+        TemplateContext {
+            data,
+            resolved: HashMap::new(),
+            resolving: Vec::new(),
+            unresolved: Vec::new(),
+            functions: FunctionRegistry::default(),
+            scopes: Vec::new(),
+            macro_calls: Vec::new(),
+            data_files: HashMap::new(),
+        }
+    }
 
-            if function_name == "lower" {
-                let func_param_1 = modified_params.index(0);
-                return func_param_1.to_lowercase();
+    fn data_file(&mut self, file: &str) -> Result<Value, TemplateError> {
+        if !self.data_files.contains_key(file) {
+            let path = Path::new(file);
+            if !path.is_file() {
+                return Err(TemplateError::new(format!("Data file does not exist: {}", file)));
             }
+            let mut data = Value::Null;
+            merge_yaml_file(path, &mut data, &MergeOptions::default(), &mut MergeTracker::default());
+            self.data_files.insert(file.to_string(), data);
+        }
+        Ok(self.data_files[file].clone())
+    }
 
-            if function_name == "len" {
-                let func_param_1 = modified_params.index(0);
-                return func_param_1.len().to_string();
-            }
+    // registers the macros of a _macros: section or file, a macro replaces a function of its name
+    fn register_macros(&mut self, definitions: &Value) -> Result<(), TemplateError> {
+        let Value::Mapping(definitions) = definitions else {
+            return Err(TemplateError::new("Macros must be a map of names to definitions"));
+        };
+        for (name, definition) in definitions {
+            let name = name
+                .as_str()
+                .ok_or_else(|| TemplateError::new(format!("Invalid macro name: {:?}", name)))?;
+            self.functions.register(Rc::new(TemplateMacro::from_value(name, definition)?));
+        }
+        Ok(())
+    }
 
-            if function_name == "is_empty" {
-                let func_param_1 = modified_params.index(0);
-                return func_param_1.is_empty().to_string();
-            }
+    fn scope_value(&self, name: &str) -> Option<&Value> {
+        self.scopes.iter().rev().find_map(|scope| scope.get(name))
+    }
 
-            if function_name == "is_not_empty" {
-                let func_param_1 = modified_params.index(0);
-                return (!func_param_1.is_empty()).to_string();
-            }
+    // an environment variable that is not set is an error, it has no place in the data to report
+    fn record_unresolved(&mut self, expr: &Expr) -> Result<(), TemplateError> {
+        if let Some(name) = env_variable_name(expr, self.data) {
+            return Err(TemplateError::new(format!(
+                "Environment variable {} is not set, give it a default with ?? or default()",
+                name
+            )));
+        }
+        self.record_unresolved_text(reference_text(expr));
+        Ok(())
+    }
 
-            if function_name == "get_env" {
-                // get the environment variable, if it does not exist, return an empty string
-                // log an error if the environment variable does not exist
-                let func_param_1 = modified_params.index(0);
+    fn record_unresolved_text(&mut self, reference: String) {
+        let unresolved = UnresolvedReference {
+            reference,
+            path: self.resolving.last().cloned().unwrap_or_default(),
+        };
+        if !self.unresolved.contains(&unresolved) {
+            self.unresolved.push(unresolved);
+        }
+    }
 
-                let environment_variables = load_environment_variables();
-                let env_var: String = environment_variables
-                    .get(func_param_1)
-                    .unwrap_or(&Value::String("".to_string()))
-                    .as_str()
-                    .unwrap()
-                    .to_string();
-                if env_var == "" {
-                    eprintln!("Environment variable not found or empty: {}", func_param_1);
+    // the value at a path of the data, only the value found has its placeholders resolved, so a
+    // value can refer to its siblings (e.g. a list item to another item of its list)
+    fn lookup(&mut self, parts: &[PathPart]) -> Result<Option<Value>, TemplateError> {
+        let data = self.data;
+        let mut current = data;
+        let mut path = String::new();
+        for (position, part) in parts.iter().enumerate() {
+            let rest = &parts[position..];
+            match current {
+                // a value inside a !raw is taken as it is
+                Value::Tagged(tagged) if tagged.tag == RAW_TAG => return select_rest(&tagged.value, rest, self),
+                // the rest of the path is in the value of a placeholder or a block
+                Value::String(text) if text.contains("{{") => {
+                    let value = self.resolve_referenced(current, &path)?;
+                    return select_rest(&value, rest, self);
+                }
+                Value::Mapping(map) if is_block(map) => {
+                    let value = self.resolve_referenced(current, &path)?;
+                    return select_rest(&value, rest, self);
                 }
-                return env_var;
+                _ => {}
             }
+            let index = match part {
+                PathPart::Key(key) => Value::String(key.clone()),
+                PathPart::Index(index) => evaluate(index, self)?,
+                // wildcards and filters select among resolved values
+                PathPart::Wildcard | PathPart::Filter(_) => {
+                    let value = self.resolve_referenced(current, &path)?;
+                    return select_rest(&value, rest, self);
+                }
+            };
+            let Some((child, child_path)) = child_entry(current, &index, &path) else {
+                return Ok(None);
+            };
+            current = child;
+            path = child_path;
+        }
+        self.resolve_referenced(current, &path).map(Some)
+    }
 
-            if function_name == "get_config" {
-                let func_param_1 = modified_params.index(0);
+    // a value of the data (or of the config) does not see the variables of the macro or loop
+    // that refers to it
+    fn resolve_referenced(&mut self, value: &Value, path: &str) -> Result<Value, TemplateError> {
+        let scopes = std::mem::take(&mut self.scopes);
+        let macro_calls = std::mem::take(&mut self.macro_calls);
+        let resolved = self.resolve_value(value, path);
+        self.scopes = scopes;
+        self.macro_calls = macro_calls;
+        resolved
+    }
 
-                let config_variables = read_config_file();
-                let config_var: String = config_variables
-                    .get(func_param_1)
-                    .unwrap_or(&Value::String("".to_string()))
-                    .as_str()
-                    .unwrap()
-                    .to_string();
-                if config_var == "" {
-                    eprintln!("Environment variable not found or empty: {}", func_param_1);
+    fn resolve_value(&mut self, value: &Value, path: &str) -> Result<Value, TemplateError> {
+        // a $if without a branch for its condition leaves nothing
+        Ok(self.resolve_entry(value, path)?.unwrap_or(Value::Null))
+    }
+
+    // the value of a map entry or list item, None when a $if block leaves it out
+    fn resolve_entry(&mut self, value: &Value, path: &str) -> Result<Option<Value>, TemplateError> {
+        match value {
+            Value::Mapping(map) if is_block(map) => self.resolve_block(map, path),
+            _ => self.resolve_node(value, path).map(Some),
+        }
+    }
+
+    fn resolve_node(&mut self, value: &Value, path: &str) -> Result<Value, TemplateError> {
+        match value {
+            Value::String(text) if text.contains("{{") => self.resolve_leaf(text, path),
+            Value::Mapping(map) => {
+                let mut resolved = serde_yaml::Mapping::new();
+                for (key, value) in map {
+                    let Some(value) = self.resolve_entry(value, &template_path(path, key))? else {
+                        continue;
+                    };
+                    let key = match key {
+                        Value::String(text) if text.contains("{{") => {
+                            Value::String(self.resolve_text(text)?)
+                        }
+                        _ => key.clone(),
+                    };
+                    resolved.insert(key, value);
+                }
+                Ok(Value::Mapping(resolved))
+            }
+            Value::Sequence(seq) => {
+                let mut resolved = Vec::new();
+                for (index, item) in seq.iter().enumerate() {
+                    let item_path = format!("{}[{}]", path, index);
+                    match (item, self.resolve_entry(item, &item_path)?) {
+                        // the items a $for generates in a list take its place
+                        (Value::Mapping(map), Some(Value::Sequence(items))) if map.contains_key(FOR_KEY) => {
+                            resolved.extend(items)
+                        }
+                        (_, Some(item)) => resolved.push(item),
+                        (_, None) => {}
+                    }
+                }
+                Ok(Value::Sequence(resolved))
+            }
+            Value::Tagged(tagged) if tagged.tag == RAW_TAG => Ok(tagged.value.clone()),
+            Value::Tagged(tagged) => Ok(Value::Tagged(Box::new(serde_yaml::value::TaggedValue {
+                tag: tagged.tag.clone(),
+                value: self.resolve_value(&tagged.value, path)?,
+            }))),
+            _ => Ok(value.clone()),
+        }
+    }
+
+    fn resolve_block(&mut self, map: &serde_yaml::Mapping, path: &str) -> Result<Option<Value>, TemplateError> {
+        let block_error = |message: String| {
+            let mut error = TemplateError::new(message);
+            error.path = Some(path.to_string());
+            error
+        };
+        let (kind, allowed) = if map.contains_key(IF_KEY) {
+            (IF_KEY, [IF_KEY, THEN_KEY, ELSE_KEY].as_slice())
+        } else {
+            (FOR_KEY, [FOR_KEY, DO_KEY].as_slice())
+        };
+        for key in map.keys() {
+            if !key.as_str().is_some_and(|key| allowed.contains(&key)) {
+                return Err(block_error(format!(
+                    "Unexpected key '{}' in a {} block, expected {}",
+                    value_to_text(key),
+                    kind,
+                    allowed.join(", ")
+                )));
+            }
+        }
+
+        if kind == IF_KEY {
+            let condition = self.resolve_expression(&map[IF_KEY], path)?;
+            let branch = if is_truthy(&condition) { map.get(THEN_KEY) } else { map.get(ELSE_KEY) };
+            return match branch {
+                Some(branch) => self.resolve_entry(branch, path),
+                None => Ok(None),
+            };
+        }
+
+        // $for: item in azure.prefix
+        let Some(body) = map.get(DO_KEY) else {
+            return Err(block_error(format!("Missing {} in a {} block", DO_KEY, FOR_KEY)));
+        };
+        let spec = map[FOR_KEY].as_str().unwrap_or_default();
+        let Some((name, source)) = spec
+            .split_once(" in ")
+            .map(|(name, source)| (name.trim(), source.trim()))
+            .filter(|(name, _)| !name.is_empty() && name.chars().all(|c| c.is_alphanumeric() || c == '_'))
+        else {
+            return Err(block_error(format!(
+                "Invalid {} '{}', expected e.g. 'item in azure.prefix'",
+                FOR_KEY, spec
+            )));
+        };
+
+        let items: Vec<Value> = match self.resolve_expression(&Value::String(source.to_string()), path)? {
+            Value::Sequence(items) => items,
+            // the entries of a map, as key and value
+            Value::Mapping(map) => map
+                .into_iter()
+                .map(|(key, value)| {
+                    let mut entry = serde_yaml::Mapping::new();
+                    entry.insert("key".into(), key);
+                    entry.insert("value".into(), value);
+                    Value::Mapping(entry)
+                })
+                .collect(),
+            Value::Null => Vec::new(),
+            other => {
+                return Err(block_error(format!(
+                    "{} '{}' needs a list or a map, got a {}",
+                    FOR_KEY,
+                    spec,
+                    value_type_name(&other)
+                )))
+            }
+        };
+
+        let length = items.len();
+        let mut generated = Vec::new();
+        for (index, item) in items.into_iter().enumerate() {
+            let mut loop_info = serde_yaml::Mapping::new();
+            loop_info.insert("index".into(), index.into());
+            loop_info.insert("first".into(), (index == 0).into());
+            loop_info.insert("last".into(), (index + 1 == length).into());
+            loop_info.insert("length".into(), length.into());
+            let scope = HashMap::from([(name.to_string(), item), ("loop".to_string(), Value::Mapping(loop_info))]);
+
+            self.scopes.push(scope);
+            let resolved = self.resolve_entry(body, &format!("{}[{}]", path, index));
+            self.scopes.pop();
+            if let Some(resolved) = resolved? {
+                generated.push(resolved);
+            }
+        }
+        Ok(Some(Value::Sequence(generated)))
+    }
+
+    // a condition or loop source: a bool, a placeholder or an expression without the {{ }}
+    fn resolve_expression(&mut self, value: &Value, path: &str) -> Result<Value, TemplateError> {
+        let Value::String(text) = value else {
+            return Ok(value.clone());
+        };
+        let template = if text.contains("{{") {
+            text.clone()
+        } else {
+            format!("{{{{ {} }}}}", text)
+        };
+        self.resolve_typed(&template).map_err(|mut e| {
+            e.path.get_or_insert(path.to_string());
+            e
+        })
+    }
+
+    fn resolve_leaf(&mut self, text: &str, path: &str) -> Result<Value, TemplateError> {
+        if let Some(resolved) = self.resolved.get(path) {
+            return Ok(resolved.clone());
+        }
+        if let Some(position) = self.resolving.iter().position(|p| p == path) {
+            let mut chain = self.resolving[position..].to_vec();
+            chain.push(path.to_string());
+            return Err(TemplateError::new(format!(
+                "Cycle in placeholders: {}",
+                chain.join(" -> ")
+            )));
+        }
+
+        self.resolving.push(path.to_string());
+        let resolved = self.resolve_typed(text);
+        self.resolving.pop();
+
+        let resolved = resolved.map_err(|mut e| {
+            // the innermost value is where the placeholder failed
+            e.path.get_or_insert(path.to_string());
+            e
+        })?;
+        self.resolved.insert(path.to_string(), resolved.clone());
+        Ok(resolved)
+    }
+
+    // a text that is a single placeholder (e.g. "{{ instance.iteration }}") takes the type
+    // of its value, anything else is interpolated into a string
+    fn resolve_typed(&mut self, text: &str) -> Result<Value, TemplateError> {
+        let parts = parse_template(text)?;
+        let placeholders: Vec<&Expr> = parts
+            .iter()
+            .filter_map(|part| match part {
+                TemplatePart::Placeholder(expr) => Some(expr),
+                TemplatePart::Text(_) => None,
+            })
+            .collect();
+        let only_whitespace = parts.iter().all(|part| match part {
+            TemplatePart::Text(text) => text.trim().is_empty(),
+            TemplatePart::Placeholder(_) => true,
+        });
+        if placeholders.len() != 1 || !only_whitespace {
+            return self.resolve_text(text).map(Value::String);
+        }
+
+        evaluate(placeholders[0], self)
+    }
+
+    fn resolve_text(&mut self, text: &str) -> Result<String, TemplateError> {
+        let mut text = text.to_string();
+        for _ in 0..MAX_PLACEHOLDER_PASSES {
+            if !text.contains("{{") {
+                return Ok(unescape_braces(&text));
+            }
+            text = replace_placeholders(&text, self)?;
+        }
+        if text.contains("{{") {
+            return Err(TemplateError::new(format!(
+                "Unresolved placeholders after {} passes: {}",
+                MAX_PLACEHOLDER_PASSES, text
+            )));
+        }
+        Ok(unescape_braces(&text))
+    }
+}
+
+// text of a value interpolated into a string, lists and maps are written inline as json
+fn value_to_text(value: &Value) -> String {
+    match value {
+        Value::Null => "".to_string(),
+        Value::Bool(value) => value.to_string(),
+        Value::Number(value) => value.to_string(),
+        Value::String(value) => value.clone(),
+        _ => serde_json::to_string(value).unwrap_or_else(|_| {
+            serde_yaml::to_string(value).unwrap().trim_end().to_string()
+        }),
+    }
+}
+
+fn replace_placeholders(output_yaml: &str, context: &mut TemplateContext) -> Result<String, TemplateError> {
+    let mut output = String::new();
+    for part in parse_template(output_yaml)? {
+        match part {
+            TemplatePart::Text(text) => output.push_str(&text),
+            // the text is final, its braces are literal
+            TemplatePart::Placeholder(expr) => output.push_str(&escape_braces(&value_to_text(&evaluate(&expr, context)?))),
+        }
+    }
+    Ok(output)
+}
+
+// a path into a document, with the syntax of the paths in templates:
+//   azure.prefix.0.text azure.prefix[0].text items[-1]   indexes, negative from the end
+//   data['key.with.dots'] data."key.with.dots"            quoted keys
+//   resources.*.tags items[*].name                        wildcards
+//   azure.prefix[?id=='storage_account'].text             filters, also !=
+// indexes and filter values are literals, there is no data to evaluate them against
+fn parse_value_path(path: &str) -> Result<Vec<PathPart>, TemplateError> {
+    let (tokens, _) = tokenize(path, 0, false)?;
+    let mut parser = ExprParser {
+        text: path,
+        tokens,
+        index: 0,
+    };
+    let parts = parser.parse_standalone_path()?;
+    parser.expect_end()?;
+
+    let is_literal = |part: &PathPart| match part {
+        PathPart::Index(index) => matches!(**index, Expr::Literal(_)),
+        PathPart::Filter(filter) => matches!(*filter.value, Expr::Literal(_)),
+        _ => true,
+    };
+    if !parts.iter().all(is_literal) {
+        return Err(TemplateError::new(format!(
+            "Only literal indexes and filter values are allowed in '{}'",
+            path
+        )));
+    }
+    Ok(parts)
+}
+
+fn literal_value(expr: &Expr) -> Result<Value, TemplateError> {
+    match expr {
+        Expr::Literal(value) => Ok(value.clone()),
+        _ => Err(TemplateError::new("Expected a literal value".to_string())),
+    }
+}
+
+// a path with a wildcard or a filter selects a list of values
+fn is_projection(parts: &[PathPart]) -> bool {
+    parts
+        .iter()
+        .any(|part| matches!(part, PathPart::Wildcard | PathPart::Filter(_)))
+}
+
+// position of an index in a sequence, a negative index counts from the end
+fn sequence_index(len: usize, index: i64) -> Option<usize> {
+    let position = if index < 0 { len as i64 + index } else { index };
+    usize::try_from(position).ok().filter(|position| *position < len)
+}
+
+// position of an index (a number, or a key like the 0 of items.0) in a sequence
+fn sequence_position(len: usize, index: &Value) -> Option<usize> {
+    match index {
+        Value::Number(number) => sequence_index(len, number.as_i64()?),
+        Value::String(key) => sequence_index(len, key.parse::<i64>().ok()?),
+        _ => None,
+    }
+}
+
+fn child_value<'v>(value: &'v Value, index: &Value) -> Option<&'v Value> {
+    match (value, index) {
+        (Value::Sequence(seq), index) => sequence_position(seq.len(), index).map(|i| &seq[i]),
+        (Value::Mapping(map), index) => map.get(index),
+        (Value::Tagged(tagged), index) => child_value(&tagged.value, index),
+        _ => None,
+    }
+}
+
+// the child at an index with its path, the path the value is resolved with
+fn child_entry<'v>(value: &'v Value, index: &Value, path: &str) -> Option<(&'v Value, String)> {
+    match (value, index) {
+        (Value::Sequence(seq), index) => {
+            sequence_position(seq.len(), index).map(|i| (&seq[i], format!("{}[{}]", path, i)))
+        }
+        (Value::Mapping(map), index) => map.get(index).map(|child| (child, template_path(path, index))),
+        (Value::Tagged(tagged), index) => child_entry(&tagged.value, index, path),
+        _ => None,
+    }
+}
+
+fn child_values(value: &Value) -> Vec<&Value> {
+    match value {
+        Value::Sequence(seq) => seq.iter().collect(),
+        Value::Mapping(map) => map.values().collect(),
+        Value::Tagged(tagged) => child_values(&tagged.value),
+        _ => Vec::new(),
+    }
+}
+
+// the values a path selects, index_of evaluates the indexes and filter values
+fn select_path<'v>(
+    value: &'v Value,
+    parts: &[PathPart],
+    index_of: &mut dyn FnMut(&Expr) -> Result<Value, TemplateError>,
+) -> Result<Vec<&'v Value>, TemplateError> {
+    let mut selected = vec![value];
+    for part in parts {
+        selected = match part {
+            PathPart::Key(key) => {
+                let key = Value::String(key.clone());
+                selected.into_iter().filter_map(|value| child_value(value, &key)).collect()
+            }
+            PathPart::Index(index) => {
+                let index = index_of(index)?;
+                selected.into_iter().filter_map(|value| child_value(value, &index)).collect()
+            }
+            PathPart::Wildcard => selected.into_iter().flat_map(child_values).collect(),
+            PathPart::Filter(filter) => {
+                let expected = index_of(&filter.value)?;
+                selected
+                    .into_iter()
+                    .flat_map(child_values)
+                    .filter(|item| filter.matches(item, &expected))
+                    .collect()
+            }
+        };
+    }
+    Ok(selected)
+}
+
+// the value at a path, paths with wildcards or filters are read with query_nested_value
+fn get_nested_value<'a>(yaml_value: &'a Value, path: &str) -> Option<&'a Value> {
+    let parts = parse_value_path(path).ok()?;
+    if is_projection(&parts) {
+        return None;
+    }
+    select_path(yaml_value, &parts, &mut literal_value)
+        .ok()?
+        .into_iter()
+        .next()
+}
+
+// the value at a path, a list of the selected values for wildcards and filters
+fn query_nested_value(yaml_value: &Value, path: &str) -> Result<Option<Value>, TemplateError> {
+    let parts = parse_value_path(path)?;
+    let selected = select_path(yaml_value, &parts, &mut literal_value)?;
+    if is_projection(&parts) {
+        return Ok(Some(Value::Sequence(selected.into_iter().cloned().collect())));
+    }
+    Ok(selected.first().map(|value| (*value).clone()))
+}
+
+// an input can be a file, a directory or a glob pattern (e.g. tests/dir1/*.yaml)
+fn resolve_input_path(input_path: &str, options: &DirectoryOptions) -> Vec<PathBuf> {
+    let path = Path::new(input_path);
+
+    if path.is_dir() {
+        let files = collect_yaml_files(path, options);
+        if files.is_empty() {
+            print_warning!("No YAML files found in directory: {}", input_path);
+        }
+        return files;
+    }
+
+    if path.exists() {
+        return vec![path.to_path_buf()];
+    }
+
+    if input_path.contains(['*', '?', '[']) {
+        let pattern_paths = glob::glob(input_path).unwrap_or_else(|e| {
+            print_error!("Invalid input pattern '{}': {}", input_path, e);
+            std::process::exit(1);
+        });
+
+        // glob yields the matches in alphabetical order
+        let mut files: Vec<PathBuf> = Vec::new();
+        for pattern_path in pattern_paths {
+            // an unreadable path fails the merge, like an unreadable input directory
+            let pattern_path = pattern_path.unwrap_or_else(|e| {
+                print_error!("Unable to read {} of the input pattern '{}': {}", e.path().display(), input_path, e.error());
+                std::process::exit(1);
+            });
+            if pattern_path.is_dir() {
+                files.extend(collect_yaml_files(&pattern_path, options));
+            } else {
+                files.push(pattern_path);
+            }
+        }
+        if files.is_empty() {
+            print_warning!("No files match the input pattern: {}", input_path);
+        }
+        return files;
+    }
+
+    print_error!("File does not exist: {}", input_path);
+    std::process::exit(1);
+}
+
+// returns the yaml files of a directory sorted by their path, so the merge order is deterministic
+fn collect_yaml_files(dir: &Path, options: &DirectoryOptions) -> Vec<PathBuf> {
+    let mut files: Vec<PathBuf> = Vec::new();
+    collect_yaml_files_into(dir, dir, options, &mut files).unwrap_or_else(|e| {
+        print_error!("{}", e);
+        std::process::exit(1);
+    });
+    files.sort();
+    files
+}
+
+fn collect_yaml_files_into(
+    root: &Path,
+    dir: &Path,
+    options: &DirectoryOptions,
+    files: &mut Vec<PathBuf>,
+) -> Result<(), String> {
+    let entries = fs::read_dir(dir).map_err(|e| format!("Unable to read directory {}: {}", dir.display(), e))?;
+
+    for entry in entries {
+        let entry = entry.map_err(|e| format!("Unable to read directory {}: {}", dir.display(), e))?;
+        let path = entry.path();
+        if path.is_dir() {
+            // a symlinked directory is not walked, it could link back to one of its parents
+            let is_symlink = entry
+                .file_type()
+                .map_err(|e| format!("Unable to read {}: {}", path.display(), e))?
+                .is_symlink();
+            if options.recursive && is_symlink {
+                print_warning!("Skipping symlinked directory {}", path.display());
+            } else if options.recursive {
+                collect_yaml_files_into(root, &path, options, files)?;
+            }
+            continue;
+        }
+
+        // patterns are matched against the path relative to the input directory, using '/'
+        let relative_path = path
+            .strip_prefix(root)
+            .unwrap()
+            .components()
+            .map(|c| c.as_os_str().to_string_lossy())
+            .collect::<Vec<_>>()
+            .join("/");
+        if options.accepts(&relative_path) {
+            files.push(path);
+        }
+    }
+    Ok(())
+}
+
+// the documents of a yaml file, with the text they are read from
+fn read_yaml_documents(path: &Path) -> Result<(String, Vec<Value>), String> {
+    let file_content =
+        fs::read_to_string(path).map_err(|e| format!("Unable to read file {}: {}", path.display(), e))?;
+
+    let mut documents = Vec::new();
+    for (document_index, document) in serde_yaml::Deserializer::from_str(&file_content).enumerate() {
+        let yaml = Value::deserialize(document).map_err(|e| match e.location() {
+            Some(location) => format!(
+                "Invalid YAML in {} (document {}, line {}, column {}): {}",
+                path.display(),
+                document_index + 1,
+                location.line(),
+                location.column(),
+                e
+            ),
+            None => format!("Invalid YAML in {} (document {}): {}", path.display(), document_index + 1, e),
+        })?;
+        documents.push(yaml);
+    }
+    Ok((file_content, documents))
+}
+
+fn merge_yaml_file(
+    path: &Path,
+    merged_yaml: &mut Value,
+    options: &MergeOptions,
+    tracker: &mut MergeTracker,
+) {
+    let (file_content, documents) = read_yaml_documents(path).unwrap_or_else(|e| {
+        print_error!("{}", e);
+        std::process::exit(1);
+    });
+    merge_yaml_documents(path, &file_content, documents, merged_yaml, options, tracker);
+}
+
+fn merge_yaml_documents(
+    path: &Path,
+    file_content: &str,
+    documents: Vec<Value>,
+    merged_yaml: &mut Value,
+    options: &MergeOptions,
+    tracker: &mut MergeTracker,
+) {
+    let document_lines = if tracker.enabled {
+        index_document_lines(file_content)
+    } else {
+        Vec::new()
+    };
+
+    // a file can hold multiple documents (--- / ...), they are merged in order
+    for (document_index, mut yaml) in documents.into_iter().enumerate() {
+        // empty documents (e.g. a leading ---) carry no data to merge
+        if yaml.is_null() {
+            continue;
+        }
+
+        // the document can declare its own list strategies, they win over the command line ones
+        let mut document_options = options.clone();
+        if let Some(directives) = yaml.as_mapping_mut().and_then(|m| m.shift_remove(MERGE_DIRECTIVES_KEY)) {
+            document_options.read_directives(&directives, path);
+
+            // protected keys stay protected for all the following documents
+            let protect = directives.get("protect").and_then(|p| p.as_sequence());
+            for protect_path in protect.into_iter().flatten() {
+                match protect_path.as_str() {
+                    Some(protect_path) => tracker.add_protected(protect_path, &path.display().to_string()),
+                    None => print_warning!("Ignoring invalid protect directive in {}: {:?}", path.display(), protect_path),
+                }
+            }
+        }
+
+        let source = MergeSource {
+            file: path.display().to_string(),
+            document: document_index + 1,
+        };
+
+        for delete_path in &document_options.delete_paths {
+            if tracker.enabled {
+                tracker.record_deletion(merged_yaml, delete_path, &source);
+            }
+            remove_nested_value(merged_yaml, delete_path);
+        }
+
+        if tracker.enabled {
+            let lines = document_lines.get(document_index).cloned().unwrap_or_default();
+            tracker.record_sources(Some(merged_yaml), &yaml, "", &source, &lines, &document_options);
+        }
+
+        merge_yaml(merged_yaml, &yaml, "", &document_options);
+    }
+}
+
+// root key of a document that holds merge directives instead of data, e.g.
+// _merge:
+//   lists:
+//     resources.resource_group.tags: replace
+const MERGE_DIRECTIVES_KEY: &str = "_merge";
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum ListStrategy {
+    AppendUnique,
+    Replace,
+    Prepend,
+    MergeByKey,
+}
+
+impl ListStrategy {
+    const NAMES: [&'static str; 4] = ["append-unique", "replace", "prepend", "merge-by-key"];
+
+    fn from_name(name: &str) -> Option<Self> {
+        match name {
+            "append-unique" => Some(ListStrategy::AppendUnique),
+            "replace" => Some(ListStrategy::Replace),
+            "prepend" => Some(ListStrategy::Prepend),
+            "merge-by-key" => Some(ListStrategy::MergeByKey),
+            _ => None,
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+struct MergeOptions {
+    list_strategy: ListStrategy,
+    // path patterns (e.g. resources.*.tags) with their own list strategy, the last match wins
+    path_strategies: Vec<(glob::Pattern, ListStrategy)>,
+    // identity fields tried in order when merging list items by key
+    key_fields: Vec<String>,
+    // path patterns with their own identity field, they also turn on merge-by-key for the path
+    path_keys: Vec<(glob::Pattern, String)>,
+    // with unset, a null in a later document removes the key instead of setting it to null
+    null_overlay: NullOverlay,
+    // paths (e.g. resources.resource_group.tags) removed before the document is merged
+    delete_paths: Vec<String>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum NullOverlay {
+    Set,
+    Unset,
+}
+
+impl NullOverlay {
+    const NAMES: [&'static str; 2] = ["set", "unset"];
+
+    fn from_name(name: &str) -> Option<Self> {
+        match name {
+            "set" => Some(NullOverlay::Set),
+            "unset" => Some(NullOverlay::Unset),
+            _ => None,
+        }
+    }
+}
+
+impl Default for MergeOptions {
+    fn default() -> Self {
+        MergeOptions {
+            list_strategy: ListStrategy::AppendUnique,
+            path_strategies: Vec::new(),
+            key_fields: vec!["id".to_string(), "name".to_string()],
+            path_keys: Vec::new(),
+            null_overlay: NullOverlay::Set,
+            delete_paths: Vec::new(),
+        }
+    }
+}
+
+impl MergeOptions {
+    fn from_matches(matches: &clap::ArgMatches) -> Self {
+        let mut options = MergeOptions::default();
+        if let Some(name) = matches.get_one::<String>("list-strategy") {
+            options.list_strategy = ListStrategy::from_name(name).unwrap();
+        }
+        for rule in matches.get_many::<String>("list-strategy-at").unwrap_or_default() {
+            let Some((path, name)) = rule.split_once('=') else {
+                print_error!("Invalid --list-strategy-at '{}', expected PATH=STRATEGY", rule);
+                std::process::exit(1);
+            };
+            options.add_path_strategy(path.trim(), name.trim(), "--list-strategy-at");
+        }
+        if let Some(key_fields) = matches.get_many::<String>("merge-key") {
+            options.key_fields = key_fields.cloned().collect();
+        }
+        for rule in matches.get_many::<String>("merge-key-at").unwrap_or_default() {
+            let Some((path, field)) = rule.split_once('=') else {
+                print_error!("Invalid --merge-key-at '{}', expected PATH=FIELD", rule);
+                std::process::exit(1);
+            };
+            options.add_path_key(path.trim(), field.trim(), "--merge-key-at");
+        }
+        if let Some(name) = matches.get_one::<String>("null-overlay") {
+            options.null_overlay = NullOverlay::from_name(name).unwrap();
+        }
+        options
+    }
+
+    fn read_directives(&mut self, directives: &Value, path: &Path) {
+        let source = path.display().to_string();
+        let lists = directives.get("lists").and_then(|l| l.as_mapping());
+        for (list_path, name) in lists.into_iter().flatten() {
+            match (list_path.as_str(), name.as_str()) {
+                (Some(list_path), Some(name)) => self.add_path_strategy(list_path, name, &source),
+                _ => print_warning!("Ignoring invalid list directive in {}: {:?}", source, list_path),
+            }
+        }
+        let keys = directives.get("keys").and_then(|k| k.as_mapping());
+        for (list_path, field) in keys.into_iter().flatten() {
+            match (list_path.as_str(), field.as_str()) {
+                (Some(list_path), Some(field)) => self.add_path_key(list_path, field, &source),
+                _ => print_warning!("Ignoring invalid key directive in {}: {:?}", source, list_path),
+            }
+        }
+        if let Some(nulls) = directives.get("nulls") {
+            match nulls.as_str().and_then(NullOverlay::from_name) {
+                Some(null_overlay) => self.null_overlay = null_overlay,
+                None => {
+                    print_error!(
+                        "Unknown nulls directive {:?} in {}, expected one of: {}",
+                        nulls,
+                        source,
+                        NullOverlay::NAMES.join(", ")
+                    );
+                    std::process::exit(1);
+                }
+            }
+        }
+        let delete_paths = directives.get("delete").and_then(|d| d.as_sequence());
+        for delete_path in delete_paths.into_iter().flatten() {
+            match delete_path.as_str() {
+                Some(delete_path) => self.delete_paths.push(delete_path.to_string()),
+                None => print_warning!("Ignoring invalid delete directive in {}: {:?}", source, delete_path),
+            }
+        }
+    }
+
+    fn add_path_strategy(&mut self, path: &str, name: &str, source: &str) {
+        let Some(strategy) = ListStrategy::from_name(name) else {
+            print_error!(
+                "Unknown list strategy '{}' in {}, expected one of: {}",
+                name,
+                source,
+                ListStrategy::NAMES.join(", ")
+            );
+            std::process::exit(1);
+        };
+        let pattern = glob::Pattern::new(path).unwrap_or_else(|e| {
+            print_error!("Invalid list path '{}' in {}: {}", path, source, e);
+            std::process::exit(1);
+        });
+        self.path_strategies.push((pattern, strategy));
+    }
+
+    fn add_path_key(&mut self, path: &str, field: &str, source: &str) {
+        let pattern = glob::Pattern::new(path).unwrap_or_else(|e| {
+            print_error!("Invalid list path '{}' in {}: {}", path, source, e);
+            std::process::exit(1);
+        });
+        self.path_keys.push((pattern, field.to_string()));
+    }
+
+    fn path_key_for(&self, path: &str) -> Option<&String> {
+        self.path_keys
+            .iter()
+            .rev()
+            .find(|(pattern, _)| pattern.matches(path))
+            .map(|(_, field)| field)
+    }
+
+    fn list_strategy_for(&self, path: &str) -> ListStrategy {
+        if let Some((_, strategy)) = self
+            .path_strategies
+            .iter()
+            .rev()
+            .find(|(pattern, _)| pattern.matches(path))
+        {
+            return *strategy;
+        }
+        if self.path_key_for(path).is_some() {
+            return ListStrategy::MergeByKey;
+        }
+        self.list_strategy
+    }
+
+    fn key_fields_for(&self, path: &str) -> Vec<&str> {
+        match self.path_key_for(path) {
+            Some(field) => vec![field.as_str()],
+            None => self.key_fields.iter().map(String::as_str).collect(),
+        }
+    }
+}
+
+fn join_path(path: &str, key: &Value) -> String {
+    let key = match key {
+        Value::String(key) => key.clone(),
+        Value::Number(key) => key.to_string(),
+        Value::Bool(key) => key.to_string(),
+        _ => "?".to_string(),
+    };
+    if path.is_empty() {
+        key
+    } else {
+        format!("{}.{}", path, key)
+    }
+}
+
+// the path of a map entry in the template context, where the resolved values are cached by path;
+// a key the path syntax would split is quoted (a["b.c"]), so it is not the nested path a.b.c
+fn template_path(path: &str, key: &Value) -> String {
+    match key {
+        Value::String(text) if text.is_empty() || text.contains(['.', '[', ']', '\'', '"']) => {
+            format!("{}[{:?}]", path, text)
+        }
+        _ => join_path(path, key),
+    }
+}
+
+// overlay nodes tagged with one of these control how they are merged, e.g. `tags: !replace [...]`
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum MergeDirective {
+    Replace,
+    Delete,
+    Append,
+    Prepend,
+}
+
+fn merge_directive(value: &Value) -> Option<(MergeDirective, &Value)> {
+    let Value::Tagged(tagged) = value else {
+        return None;
+    };
+    let directive = if tagged.tag == "replace" {
+        MergeDirective::Replace
+    } else if tagged.tag == "delete" {
+        MergeDirective::Delete
+    } else if tagged.tag == "append" {
+        MergeDirective::Append
+    } else if tagged.tag == "prepend" {
+        MergeDirective::Prepend
+    } else {
+        return None;
+    };
+    Some((directive, &tagged.value))
+}
+
+fn is_delete_directive(value: &Value) -> bool {
+    matches!(merge_directive(value), Some((MergeDirective::Delete, _)))
+}
+
+// copy of a value without merge directive tags, nodes tagged !delete are dropped (and keys
+// set to null, with unset nulls), other tags (e.g. !Thing) are kept as they are
+fn strip_merge_directives(value: &Value, nulls: NullOverlay) -> Value {
+    if let Some((_, inner)) = merge_directive(value) {
+        return strip_merge_directives(inner, nulls);
+    }
+    match value {
+        Value::Mapping(map) => Value::Mapping(
+            map.iter()
+                .filter(|(_, v)| !is_delete_directive(v) && (!v.is_null() || nulls == NullOverlay::Set))
+                .map(|(k, v)| (k.clone(), strip_merge_directives(v, nulls)))
+                .collect(),
+        ),
+        Value::Sequence(seq) => Value::Sequence(strip_merge_directives_seq(seq, nulls)),
+        Value::Tagged(tagged) => Value::Tagged(Box::new(serde_yaml::value::TaggedValue {
+            tag: tagged.tag.clone(),
+            value: strip_merge_directives(&tagged.value, nulls),
+        })),
+        _ => value.clone(),
+    }
+}
+
+fn strip_merge_directives_seq(seq: &[Value], nulls: NullOverlay) -> Vec<Value> {
+    seq.iter()
+        .filter(|v| !is_delete_directive(v))
+        .map(|v| strip_merge_directives(v, nulls))
+        .collect()
+}
+
+fn apply_merge_directive(base: &mut Value, directive: MergeDirective, value: &Value, path: &str, nulls: NullOverlay) {
+    match (directive, base) {
+        (MergeDirective::Replace, base) => {
+            *base = strip_merge_directives(value, nulls);
+        }
+        (MergeDirective::Delete, base) => {
+            // keys of a mapping are removed by the caller, anywhere else the node becomes null
+            *base = Value::Null;
+        }
+        (MergeDirective::Append, Value::Sequence(base_seq)) if value.is_sequence() => {
+            base_seq.extend(strip_merge_directives_seq(value.as_sequence().unwrap(), nulls));
+        }
+        (MergeDirective::Prepend, Value::Sequence(base_seq)) if value.is_sequence() => {
+            let mut new_seq = strip_merge_directives_seq(value.as_sequence().unwrap(), nulls);
+            new_seq.append(base_seq);
+            *base_seq = new_seq;
+        }
+        (_, base) => {
+            if !base.is_null() {
+                print_warning!("!{:?} at '{}' expects two lists, the value is replaced", directive, path);
+            }
+            *base = strip_merge_directives(value, nulls);
+        }
+    }
+}
+
+// path is the dotted location of base in the merged document, it selects the list strategy
+fn merge_yaml(base: &mut Value, other: &Value, path: &str, options: &MergeOptions) {
+    if let Some((directive, value)) = merge_directive(other) {
+        apply_merge_directive(base, directive, value, path, options.null_overlay);
+        return;
+    }
+
+    match (base, other) {
+        (Value::Mapping(base_map), Value::Mapping(other_map)) => {
+            for (key, value) in other_map {
+                if is_delete_directive(value)
+                    || (value.is_null() && options.null_overlay == NullOverlay::Unset)
+                {
+                    base_map.shift_remove(key);
+                    continue;
+                }
+                let key_path = join_path(path, key);
+                merge_yaml(
+                    base_map.entry(key.clone()).or_insert(Value::Null),
+                    value,
+                    &key_path,
+                    options,
+                );
+            }
+        }
+        (Value::Sequence(base_seq), Value::Sequence(other_seq)) => match options.list_strategy_for(path) {
+            // keyed items are merged one by one, so they keep their own directives
+            ListStrategy::MergeByKey => merge_sequence_by_key(base_seq, other_seq, path, options),
+            ListStrategy::AppendUnique => {
+                for value in strip_merge_directives_seq(other_seq, options.null_overlay) {
+                    // if the value already exists in the base sequence, skip it
+                    if base_seq.contains(&value) {
+                        continue;
+                    }
+                    base_seq.push(value);
+                }
+            }
+            ListStrategy::Replace => {
+                *base_seq = strip_merge_directives_seq(other_seq, options.null_overlay);
+            }
+            // like append-unique it skips the items already present, unlike the !prepend tag
+            ListStrategy::Prepend => {
+                let mut new_seq: Vec<Value> = strip_merge_directives_seq(other_seq, options.null_overlay)
+                    .into_iter()
+                    .filter(|value| !base_seq.contains(value))
+                    .collect();
+                new_seq.append(base_seq);
+                *base_seq = new_seq;
+            }
+        },
+        (base, other) => {
+            *base = strip_merge_directives(other, options.null_overlay);
+        }
+    }
+}
+
+// items that are mappings with the same identity field value are deep merged in place,
+// everything else is appended when not already present
+fn merge_sequence_by_key(
+    base_seq: &mut Vec<Value>,
+    other_seq: &[Value],
+    path: &str,
+    options: &MergeOptions,
+) {
+    let key_fields = options.key_fields_for(path);
+    for value in other_seq {
+        match keyed_position(base_seq, value, &key_fields) {
+            Some(position) if is_delete_directive(value) => {
+                base_seq.remove(position);
+            }
+            Some(position) => merge_yaml(&mut base_seq[position], value, path, options),
+            None if is_delete_directive(value) => {}
+            None => {
+                let value = strip_merge_directives(value, options.null_overlay);
+                if !base_seq.contains(&value) {
+                    base_seq.push(value);
+                }
+            }
+        }
+    }
+}
+
+// position of the base item with the same identity as value
+fn keyed_position(base_seq: &[Value], value: &Value, key_fields: &[&str]) -> Option<usize> {
+    // an item can be tagged (e.g. !delete {id: storage_account}), its key is read inside the tag
+    let item = merge_directive(value).map_or(value, |(_, inner)| inner);
+    // the first identity field the item holds is the one used to match
+    let (field, key_value) = key_fields
+        .iter()
+        .filter(|_| item.is_mapping())
+        .find_map(|field| item.get(*field).map(|key_value| (*field, key_value)))?;
+    base_seq
+        .iter()
+        .position(|item| item.is_mapping() && item.get(field) == Some(key_value))
+}
+
+#[derive(Debug, Clone)]
+struct MergeSource {
+    file: String,
+    // 1-based, like the lines
+    document: usize,
+}
+
+#[derive(Debug, Clone)]
+struct MergeLocation {
+    source: MergeSource,
+    line: Option<usize>,
+}
+
+impl MergeLocation {
+    fn to_value(&self) -> Value {
+        let mut location = serde_yaml::Mapping::new();
+        location.insert("file".into(), self.source.file.clone().into());
+        location.insert("document".into(), self.source.document.into());
+        location.insert("line".into(), self.line.map_or(Value::Null, Value::from));
+        Value::Mapping(location)
+    }
+}
+
+impl std::fmt::Display for MergeLocation {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        match self.line {
+            Some(line) => write!(f, "{} (document {}, line {})", self.source.file, self.source.document, line),
+            None => write!(f, "{} (document {})", self.source.file, self.source.document),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum ConflictKind {
+    // a value of another type (e.g. a map replaced by a string) replaced the previous one
+    TypeChange,
+    // a value of the same type replaced the previous one
+    Override,
+    // the key was removed
+    Delete,
+}
+
+#[derive(Debug)]
+struct MergeConflict {
+    path: String,
+    kind: ConflictKind,
+    protected: bool,
+    previous_type: &'static str,
+    new_type: &'static str,
+    previous: Option<MergeLocation>,
+    location: MergeLocation,
+}
+
+impl MergeConflict {
+    fn to_value(&self) -> Value {
+        let mut conflict = serde_yaml::Mapping::new();
+        conflict.insert("path".into(), self.path.clone().into());
+        conflict.insert("kind".into(), format!("{:?}", self.kind).into());
+        conflict.insert("protected".into(), self.protected.into());
+        conflict.insert("previous_type".into(), self.previous_type.into());
+        conflict.insert("new_type".into(), self.new_type.into());
+        conflict.insert(
+            "previous".into(),
+            self.previous.as_ref().map_or(Value::Null, MergeLocation::to_value),
+        );
+        conflict.insert("location".into(), self.location.to_value());
+        Value::Mapping(conflict)
+    }
+
+    // conflicts that fail a --strict merge
+    fn is_strict_violation(&self) -> bool {
+        self.kind == ConflictKind::TypeChange || self.protected
+    }
+}
+
+impl std::fmt::Display for MergeConflict {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        let previous = self
+            .previous
+            .as_ref()
+            .map_or("unknown location".to_string(), |previous| previous.to_string());
+        write!(
+            f,
+            "{:?}{} at '{}': {} from {} -> {} from {}",
+            self.kind,
+            if self.protected { " of protected key" } else { "" },
+            self.path,
+            self.previous_type,
+            previous,
+            self.new_type,
+            self.location
+        )
+    }
+}
+
+fn value_type_name(value: &Value) -> &'static str {
+    match value {
+        Value::Null => "null",
+        Value::Bool(_) => "bool",
+        Value::Number(_) => "number",
+        Value::String(_) => "string",
+        Value::Sequence(_) => "list",
+        Value::Mapping(_) => "map",
+        Value::Tagged(_) => "tagged",
+    }
+}
+
+// keeps, for every leaf path, the location of the document that last set it.
+// lists are leaves: the location is the last document that merged into the list.
+// while doing so it also collects the values that a document overrides
+#[derive(Debug, Default)]
+struct MergeTracker {
+    enabled: bool,
+    locations: HashMap<String, MergeLocation>,
+    conflicts: Vec<MergeConflict>,
+    // path patterns that no later document is allowed to change under --strict
+    protected: Vec<glob::Pattern>,
+}
+
+impl MergeTracker {
+    fn add_protected(&mut self, path: &str, source: &str) {
+        let pattern = glob::Pattern::new(path).unwrap_or_else(|e| {
+            print_error!("Invalid protected path '{}' in {}: {}", path, source, e);
+            std::process::exit(1);
+        });
+        self.protected.push(pattern);
+    }
+
+    // a path is protected when it, or one of its parents, matches a protected pattern
+    fn is_protected(&self, path: &str) -> bool {
+        let mut current = path;
+        loop {
+            if self.protected.iter().any(|pattern| pattern.matches(current)) {
+                return true;
+            }
+            match current.rfind(['.', '[']) {
+                Some(position) => current = &current[..position],
+                None => return false,
+            }
+        }
+    }
+
+    // location of the value at a path, or of the nearest parent that has one (e.g. a list)
+    fn location_of(&self, path: &str) -> Option<&MergeLocation> {
+        let mut path = path;
+        loop {
+            if let Some(location) = self.locations.get(path) {
+                return Some(location);
+            }
+            path = &path[..path.rfind(['.', '['])?];
+        }
+    }
+
+    // the location of the first leaf under path, used as the location of a replaced subtree
+    fn first_location(&self, value: &Value, path: &str) -> Option<MergeLocation> {
+        match value {
+            Value::Mapping(map) if !map.is_empty() => map
+                .iter()
+                .find_map(|(key, value)| self.first_location(value, &join_path(path, key))),
+            _ => self.location_of(path).cloned(),
+        }
+    }
+
+    fn record_conflict(
+        &mut self,
+        kind: ConflictKind,
+        path: &str,
+        base: &Value,
+        overlay: &Value,
+        location: MergeLocation,
+    ) {
+        let conflict = MergeConflict {
+            path: path.to_string(),
+            kind,
+            protected: self.is_protected(path),
+            previous_type: value_type_name(base),
+            new_type: value_type_name(overlay),
+            previous: self.first_location(base, path),
+            location,
+        };
+        self.conflicts.push(conflict);
+    }
+
+    // removal of path (e.g. a _merge.delete entry) from the merged document
+    fn record_deletion(&mut self, base: &Value, path: &str, source: &MergeSource) {
+        if let Some(previous) = get_nested_value(base, path) {
+            let location = MergeLocation {
+                source: source.clone(),
+                line: None,
+            };
+            self.record_conflict(ConflictKind::Delete, path, previous, &Value::Null, location);
+        }
+    }
+
+    // walks the overlay document along the merged document as it is before the overlay is merged
+    fn record_sources(
+        &mut self,
+        base: Option<&Value>,
+        overlay: &Value,
+        path: &str,
+        source: &MergeSource,
+        lines: &HashMap<String, usize>,
+        options: &MergeOptions,
+    ) {
+        let location = MergeLocation {
+            source: source.clone(),
+            line: lines.get(path).copied(),
+        };
+        let base = base.filter(|base| !base.is_null());
+
+        let (directive, overlay) = match merge_directive(overlay) {
+            Some((directive, inner)) => (Some(directive), inner),
+            None => (None, overlay),
+        };
+
+        match (directive, base) {
+            (Some(MergeDirective::Delete), Some(base)) => {
+                self.record_conflict(ConflictKind::Delete, path, base, &Value::Null, location);
+                return;
+            }
+            (Some(MergeDirective::Delete), None) => return,
+            (Some(MergeDirective::Replace), Some(base)) => {
+                let overlay = strip_merge_directives(overlay, options.null_overlay);
+                if *base != overlay {
+                    let kind = if value_type_name(base) == value_type_name(&overlay) {
+                        ConflictKind::Override
+                    } else {
+                        ConflictKind::TypeChange
+                    };
+                    self.record_conflict(kind, path, base, &overlay, location.clone());
+                }
+                // the replaced subtree does not merge anymore, its leaves are all new
+                self.record_sources(None, &overlay, path, source, lines, options);
+                return;
+            }
+            _ => {}
+        }
+
+        match (base, overlay) {
+            (Some(Value::Mapping(base_map)), Value::Mapping(map)) => {
+                for (key, value) in map {
+                    let key_path = join_path(path, key);
+                    if value.is_null() && options.null_overlay == NullOverlay::Unset {
+                        if let Some(previous) = base_map.get(key).filter(|v| !v.is_null()) {
+                            let location = MergeLocation {
+                                source: source.clone(),
+                                line: lines.get(&key_path).copied(),
+                            };
+                            self.record_conflict(ConflictKind::Delete, &key_path, previous, value, location);
+                        }
+                        continue;
+                    }
+                    self.record_sources(base_map.get(key), value, &key_path, source, lines, options);
+                }
+            }
+            (base, Value::Mapping(map)) => {
+                if let Some(base) = base {
+                    self.record_conflict(ConflictKind::TypeChange, path, base, overlay, location);
+                }
+                for (key, value) in map {
+                    if value.is_null() && options.null_overlay == NullOverlay::Unset {
+                        continue;
+                    }
+                    self.record_sources(None, value, &join_path(path, key), source, lines, options);
+                }
+            }
+            (Some(Value::Sequence(base_seq)), Value::Sequence(seq))
+                if directive.is_none() && options.list_strategy_for(path) == ListStrategy::MergeByKey =>
+            {
+                // matched items merge like maps, so their overrides are recorded under the base item
+                let key_fields = options.key_fields_for(path);
+                for (index, value) in seq.iter().enumerate() {
+                    if let Some(position) = keyed_position(base_seq, value, &key_fields) {
+                        let item_path = format!("{}[{}]", path, position);
+                        let item_lines = rebase_lines(lines, &format!("{}[{}]", path, index), &item_path);
+                        self.record_sources(Some(&base_seq[position]), value, &item_path, source, &item_lines, options);
+                    }
+                }
+                self.locations.insert(path.to_string(), location);
+            }
+            (base, _) => {
+                match base {
+                    Some(base) if value_type_name(base) != value_type_name(overlay) => {
+                        self.record_conflict(ConflictKind::TypeChange, path, base, overlay, location.clone());
+                    }
+                    // lists merge, they only override when they are replaced
+                    Some(Value::Sequence(_))
+                        if directive.is_some() || options.list_strategy_for(path) != ListStrategy::Replace => {}
+                    Some(base) if base != overlay => {
+                        self.record_conflict(ConflictKind::Override, path, base, overlay, location.clone());
+                    }
+                    _ => {}
+                }
+                self.locations.insert(path.to_string(), location);
+            }
+        }
+    }
+
+    // the provenance of every leaf still present in the merged document, in document order
+    fn provenance(&self, merged_yaml: &Value) -> Vec<(String, &MergeLocation)> {
+        let mut report = Vec::new();
+        self.collect_provenance(merged_yaml, "", &mut report);
+        report
+    }
+
+    fn collect_provenance<'a>(
+        &'a self,
+        value: &Value,
+        path: &str,
+        report: &mut Vec<(String, &'a MergeLocation)>,
+    ) {
+        match value {
+            Value::Mapping(map) if !map.is_empty() => {
+                for (key, value) in map {
+                    self.collect_provenance(value, &join_path(path, key), report);
+                }
+            }
+            _ => {
+                if let Some(location) = self.locations.get(path) {
+                    report.push((path.to_string(), location));
+                }
+            }
+        }
+    }
+}
+
+// the lines under the path of an overlay list item, moved to the path of the item it merges into
+fn rebase_lines(lines: &HashMap<String, usize>, from: &str, to: &str) -> HashMap<String, usize> {
+    lines
+        .iter()
+        .filter_map(|(path, line)| {
+            let rest = path.strip_prefix(from)?;
+            (rest.is_empty() || rest.starts_with(['.', '['])).then(|| (format!("{}{}", to, rest), *line))
+        })
+        .collect()
+}
+
+// serde_yaml values carry no positions, so the lines of the keys are read with a second,
+// event based parser: one map of dotted path -> line per document
+fn index_document_lines(content: &str) -> Vec<HashMap<String, usize>> {
+    let mut index = LineIndex::default();
+    if let Err(e) = yaml_rust2::parser::Parser::new_from_str(content).load(&mut index, true) {
+        print_warning!("Unable to read line numbers: {}", e);
+    }
+    index.documents
+}
+
+enum LineIndexFrame {
+    Mapping { path: String, key: Option<(String, usize)> },
+    Sequence { path: String, index: usize },
+    // a complex key (a mapping or a list), its nodes are not indexed
+    ComplexKey,
+}
+
+#[derive(Default)]
+struct LineIndex {
+    documents: Vec<HashMap<String, usize>>,
+    frames: Vec<LineIndexFrame>,
+}
+
+impl yaml_rust2::parser::MarkedEventReceiver for LineIndex {
+    fn on_event(&mut self, event: yaml_rust2::Event, mark: yaml_rust2::scanner::Marker) {
+        use yaml_rust2::Event;
+
+        match event {
+            Event::DocumentStart => {
+                self.documents.push(HashMap::new());
+                self.frames.clear();
+                return;
+            }
+            Event::MappingEnd | Event::SequenceEnd => {
+                self.frames.pop();
+                return;
+            }
+            Event::Scalar(..) | Event::Alias(..) | Event::MappingStart(..) | Event::SequenceStart(..) => {}
+            _ => return,
+        }
+
+        // a node is either a mapping key, a mapping value, a sequence item or the document root
+        let path = match self.frames.last_mut() {
+            Some(LineIndexFrame::Mapping { key: key @ None, .. }) => {
+                let text = match &event {
+                    Event::Scalar(text, ..) => text.clone(),
+                    _ => "?".to_string(),
+                };
+                *key = Some((text, mark.line()));
+                // the end of a complex key pops its own frame, not the mapping holding it
+                if matches!(event, Event::MappingStart(..) | Event::SequenceStart(..)) {
+                    self.frames.push(LineIndexFrame::ComplexKey);
+                }
+                return;
+            }
+            Some(LineIndexFrame::ComplexKey) => {
+                if matches!(event, Event::MappingStart(..) | Event::SequenceStart(..)) {
+                    self.frames.push(LineIndexFrame::ComplexKey);
+                }
+                return;
+            }
+            Some(LineIndexFrame::Mapping { path, key }) => {
+                let (text, line) = key.take().unwrap();
+                let path = join_path(path, &Value::String(text));
+                if let Some(lines) = self.documents.last_mut() {
+                    lines.insert(path.clone(), line);
+                }
+                path
+            }
+            Some(LineIndexFrame::Sequence { path, index }) => {
+                *index += 1;
+                format!("{}[{}]", path, *index - 1)
+            }
+            None => String::new(),
+        };
+
+        match event {
+            Event::MappingStart(..) => self.frames.push(LineIndexFrame::Mapping { path, key: None }),
+            Event::SequenceStart(..) => self.frames.push(LineIndexFrame::Sequence { path, index: 0 }),
+            _ => {}
+        }
+    }
+}
+
+// TODO Features
+// - read secrets from a file that is secure in the filesystem
+
+const CONFIG_FILE_NAME: &str = "config.yaml";
+const CONFIG_ENV: &str = "YW_CONFIG";
+
+// config of get_config and lookup_config, loaded once; its layers are merged in order, last wins:
+//   $XDG_CONFIG_HOME/yw/config.yaml (or ~/.config/yw/config.yaml)
+//   config.yaml of the project, the nearest directory up from the working directory with one
+//   the files of YW_CONFIG, separated like PATH
+//   the files of --config
+struct Config {
+    value: Value,
+    files: Vec<PathBuf>,
+}
+
+// the --config files, the config is only loaded by the first template that reads it
+static CONFIG_FILES: std::sync::OnceLock<Vec<String>> = std::sync::OnceLock::new();
+static CONFIG: std::sync::OnceLock<Result<Config, String>> = std::sync::OnceLock::new();
+
+fn config() -> Result<&'static Config, TemplateError> {
+    CONFIG
+        .get_or_init(|| load_config(CONFIG_FILES.get().map_or(&[], Vec::as_slice)))
+        .as_ref()
+        .map_err(|e| TemplateError::new(e.clone()))
+}
+
+// files that mark the root of a project, the search for its config.yaml stops there
+const PROJECT_MARKERS: [&str; 2] = [".git", "Cargo.toml"];
+
+// the config.yaml of the nearest directory up from dir that has one, up to the project root;
+// outside a project only dir itself
+fn project_config(dir: &Path) -> Option<PathBuf> {
+    let is_root = |dir: &Path| PROJECT_MARKERS.iter().any(|marker| dir.join(marker).exists());
+    let root = dir.ancestors().find(|dir| is_root(dir)).unwrap_or(dir);
+    for dir in dir.ancestors() {
+        let path = dir.join(CONFIG_FILE_NAME);
+        if path.is_file() {
+            return Some(path);
+        }
+        if dir == root {
+            break;
+        }
+    }
+    None
+}
+
+fn config_layers(config_files: &[String]) -> Result<Vec<PathBuf>, String> {
+    let mut layers = Vec::new();
+
+    let user_config_dir = std::env::var_os("XDG_CONFIG_HOME")
+        .map(PathBuf::from)
+        .filter(|dir| dir.is_absolute())
+        .or_else(|| std::env::var_os("HOME").map(|home| Path::new(&home).join(".config")));
+    if let Some(dir) = user_config_dir {
+        layers.extend(Some(dir.join("yw").join(CONFIG_FILE_NAME)).filter(|path| path.is_file()));
+    }
+    if let Ok(current_dir) = std::env::current_dir() {
+        layers.extend(project_config(&current_dir));
+    }
+
+    // files given explicitly have to exist
+    let mut explicit: Vec<PathBuf> = std::env::var_os(CONFIG_ENV)
+        .map(|paths| std::env::split_paths(&paths).filter(|path| !path.as_os_str().is_empty()).collect())
+        .unwrap_or_default();
+    explicit.extend(config_files.iter().map(PathBuf::from));
+    if let Some(path) = explicit.iter().find(|path| !path.is_file()) {
+        return Err(format!("Config file does not exist: {}", path.display()));
+    }
+    layers.extend(explicit);
+
+    // a file found twice (e.g. --config config.yaml) is merged once, at its last place
+    let identity = |path: &PathBuf| fs::canonicalize(path).unwrap_or_else(|_| path.clone());
+    let identities: Vec<PathBuf> = layers.iter().map(identity).collect();
+    Ok(layers
+        .into_iter()
+        .enumerate()
+        .filter(|(index, _)| !identities[index + 1..].contains(&identities[*index]))
+        .map(|(_, path)| path)
+        .collect())
+}
+
+fn load_config(config_files: &[String]) -> Result<Config, String> {
+    let files = config_layers(config_files)?;
+    let mut value = Value::Null;
+    for file in &files {
+        let (file_content, documents) = read_yaml_documents(file)?;
+        merge_yaml_documents(
+            file,
+            &file_content,
+            documents,
+            &mut value,
+            &MergeOptions::default(),
+            &mut MergeTracker::default(),
+        );
+    }
+    Ok(Config { value, files })
+}
+
+// the config, an error when no config file was found
+fn config_value() -> Result<&'static Value, TemplateError> {
+    let config = config()?;
+    if config.files.is_empty() {
+        return Err(TemplateError::new(format!(
+            "No config file found: pass --config, set {} or add a {} to the project root",
+            CONFIG_ENV, CONFIG_FILE_NAME
+        )));
+    }
+    Ok(&config.value)
+}
+
+// namespace of the environment variables in templates, e.g. {{ env.YW_STAGE }}
+const ENV_NAMESPACE: &str = "env";
+
+// environment variables of the process always exposed to templates, --env-allow adds patterns
+const ENV_ALLOWED_PREFIX: &str = "YW_*";
+
+// environment variables exposed to templates, loaded once: the variables of the --env-file
+// files and the allowed ones of the process, which win over the files
+struct Environment {
+    variables: BTreeMap<String, String>,
+    allowed: Vec<glob::Pattern>,
+}
+
+static ENVIRONMENT: std::sync::OnceLock<Environment> = std::sync::OnceLock::new();
+
+fn environment() -> &'static Environment {
+    ENVIRONMENT.get_or_init(|| load_environment(&[], &[]))
+}
+
+impl Environment {
+    // None for an allowed variable that is not set, an error for one that is not allowed
+    fn get(&self, name: &str) -> Result<Option<&str>, TemplateError> {
+        if let Some(value) = self.variables.get(name) {
+            return Ok(Some(value));
+        }
+        if self.allowed.iter().any(|pattern| pattern.matches(name)) {
+            return Ok(None);
+        }
+        Err(TemplateError::new(format!(
+            "Environment variable {} is not allowed, expose it with --env-allow",
+            name
+        )))
+    }
+
+    fn to_value(&self) -> Value {
+        Value::Mapping(
+            self.variables
+                .iter()
+                .map(|(name, value)| (Value::String(name.clone()), Value::String(value.clone())))
+                .collect(),
+        )
+    }
+}
+
+fn load_environment(env_files: &[String], allow: &[String]) -> Environment {
+    let allowed: Vec<glob::Pattern> = std::iter::once(ENV_ALLOWED_PREFIX)
+        .chain(allow.iter().map(String::as_str))
+        .map(|pattern| {
+            glob::Pattern::new(pattern).unwrap_or_else(|e| {
+                print_error!("Invalid --env-allow '{}': {}", pattern, e);
+                std::process::exit(1);
+            })
+        })
+        .collect();
+
+    let mut variables = BTreeMap::new();
+    for env_file in env_files {
+        variables.extend(read_env_file(Path::new(env_file)));
+    }
+    for (name, value) in std::env::vars_os() {
+        let (Ok(name), Ok(value)) = (name.into_string(), value.into_string()) else {
+            continue;
+        };
+        if allowed.iter().any(|pattern| pattern.matches(&name)) {
+            variables.insert(name, value);
+        }
+    }
+    Environment { variables, allowed }
+}
+
+// the NAME=VALUE lines of a .env file; blank lines and # comments are skipped, a line can start
+// with export, a value can be quoted ('...' as it is, "..." with \n and \")
+fn read_env_file(path: &Path) -> Vec<(String, String)> {
+    let content = fs::read_to_string(path).unwrap_or_else(|e| {
+        print_error!("Unable to read env file {}: {}", path.display(), e);
+        std::process::exit(1);
+    });
+
+    let mut variables = Vec::new();
+    for (index, line) in content.lines().enumerate() {
+        let line = line.trim();
+        if line.is_empty() || line.starts_with('#') {
+            continue;
+        }
+        let line = line.strip_prefix("export ").unwrap_or(line);
+        let Some((name, value)) = line
+            .split_once('=')
+            .map(|(name, value)| (name.trim(), value.trim()))
+            .filter(|(name, _)| !name.is_empty() && name.chars().all(|c| c.is_ascii_alphanumeric() || c == '_'))
+        else {
+            print_error!("Invalid line {} in {}, expected NAME=VALUE: {}", index + 1, path.display(), line);
+            std::process::exit(1);
+        };
+
+        let quoted = |quote: char| value.len() >= 2 && value.starts_with(quote) && value.ends_with(quote);
+        let value = if quoted('"') {
+            value[1..value.len() - 1].replace("\\n", "\n").replace("\\\"", "\"")
+        } else if quoted('\'') {
+            value[1..value.len() - 1].to_string()
+        } else {
+            // a comment after an unquoted value
+            value.split(" #").next().unwrap_or_default().trim_end().to_string()
+        };
+        variables.push((name.to_string(), value));
+    }
+    variables
+}
+
+// template expressions, the text between {{ and }}:
+//   'text' "text" 12 -3.5 true false null      literals
+//   root.level1.name azure.prefix[0].text      paths into the merged data
+//   items[-1] data['key.with.dots'] a."b.c"    negative indexes (from the end) and quoted keys
+//   items[*].name azure.prefix[?id=='sa'].text wildcards and filters, select a list of values
+//   concat(upper(data.name), '-', 1)           function calls, nested
+//   data.name | lower | concat('-x')           pipes, the value is the first parameter of the filter
+//   data.name ?? 'x'  data.name | default('x') fallbacks for a path that is missing or null
+//   count * 2 + 1  stage == 'prod'  a and not b operators, from the loosest:
+//       or ||, and &&, not !, == != < <= > >=, + -, * / %, unary -, ??, | (pipes bind tightest)
+
+#[derive(Debug, Clone, PartialEq)]
+enum Token {
+    Ident(String),
+    Str(String),
+    Number(serde_yaml::Number),
+    Dot,
+    Comma,
+    Pipe,
+    Minus,
+    LParen,
+    RParen,
+    LBracket,
+    RBracket,
+    Star,
+    Question,
+    Equal,
+    NotEqual,
+    Coalesce,
+    Plus,
+    Slash,
+    Percent,
+    Less,
+    LessEqual,
+    Greater,
+    GreaterEqual,
+    And,
+    Or,
+    Not,
+}
+
+#[derive(Debug, Clone)]
+enum PathPart {
+    Key(String),
+    Index(Box<Expr>),
+    // .* or [*], every item of a list or value of a map
+    Wildcard,
+    // [?field == value], the items whose field equals the value
+    Filter(PathFilter),
+}
+
+#[derive(Debug, Clone)]
+struct PathFilter {
+    field: Vec<String>,
+    equal: bool,
+    value: Box<Expr>,
+}
+
+impl PathFilter {
+    fn matches(&self, item: &Value, expected: &Value) -> bool {
+        let actual = self
+            .field
+            .iter()
+            .try_fold(item, |current, key| child_value(current, &Value::String(key.clone())));
+        (actual == Some(expected)) == self.equal
+    }
+}
+
+#[derive(Debug, Clone)]
+struct Call {
+    name: String,
+    args: Vec<Expr>,
+    // column of the name in the template text, for error messages
+    column: usize,
+}
+
+#[derive(Debug, Clone)]
+enum Expr {
+    Literal(Value),
+    Path(Vec<PathPart>),
+    Call(Call),
+    Pipe(Box<Expr>, Vec<Call>),
+    // value ?? fallback
+    Coalesce(Box<Expr>, Box<Expr>),
+    Unary(UnaryOp, Box<Expr>),
+    Binary(BinaryOp, Box<Expr>, Box<Expr>),
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum UnaryOp {
+    Not,
+    Negate,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum BinaryOp {
+    Or,
+    And,
+    Equal,
+    NotEqual,
+    Less,
+    LessEqual,
+    Greater,
+    GreaterEqual,
+    Add,
+    Subtract,
+    Multiply,
+    Divide,
+    Remainder,
+}
+
+impl BinaryOp {
+    fn symbol(&self) -> &'static str {
+        match self {
+            BinaryOp::Or => "or",
+            BinaryOp::And => "and",
+            BinaryOp::Equal => "==",
+            BinaryOp::NotEqual => "!=",
+            BinaryOp::Less => "<",
+            BinaryOp::LessEqual => "<=",
+            BinaryOp::Greater => ">",
+            BinaryOp::GreaterEqual => ">=",
+            BinaryOp::Add => "+",
+            BinaryOp::Subtract => "-",
+            BinaryOp::Multiply => "*",
+            BinaryOp::Divide => "/",
+            BinaryOp::Remainder => "%",
+        }
+    }
+}
+
+#[derive(Debug)]
+enum TemplatePart {
+    Text(String),
+    Placeholder(Expr),
+}
+
+fn column_of(text: &str, position: usize) -> usize {
+    text[..position.min(text.len())].chars().count() + 1
+}
+
+fn syntax_error(text: &str, position: usize, message: &str) -> TemplateError {
+    TemplateError::new(format!(
+        "Syntax error at column {}: {} in '{}'",
+        column_of(text, position),
+        message,
+        text
+    ))
+}
+
+// splits a text into plain text and {{ }} placeholders
+fn parse_template(text: &str) -> Result<Vec<TemplatePart>, TemplateError> {
+    let mut parts = Vec::new();
+    let mut rest_start = 0;
+
+    while let Some(found) = text[rest_start..].find("{{") {
+        let open = rest_start + found;
+        // \{{ is a literal {{
+        if text[..open].ends_with('\\') {
+            parts.push(TemplatePart::Text(format!("{}{}", &text[rest_start..open - 1], ESCAPED_OPEN)));
+            rest_start = open + 2;
+            continue;
+        }
+        if open > rest_start {
+            parts.push(TemplatePart::Text(text[rest_start..open].to_string()));
+        }
+        let (tokens, close) = tokenize(text, open + 2, true)?;
+        let mut parser = ExprParser { text, tokens, index: 0 };
+        let expr = parser.parse_expression()?;
+        parser.expect_end()?;
+        parts.push(TemplatePart::Placeholder(expr));
+        rest_start = close + 2;
+    }
+
+    if rest_start < text.len() {
+        parts.push(TemplatePart::Text(text[rest_start..].to_string()));
+    }
+    Ok(parts)
+}
+
+// tokens from start up to the closing }} (or the end of a path), with their offsets, and the
+// offset where they end; a dash between key characters is part of the key (e.g. out-1), in
+// placeholders as in paths, so subtracting needs a space (iteration - 1)
+fn tokenize(text: &str, start: usize, placeholder: bool) -> Result<(Vec<(Token, usize)>, usize), TemplateError> {
+    let mut tokens: Vec<(Token, usize)> = Vec::new();
+    let mut chars = text[start..].char_indices().map(|(i, c)| (start + i, c)).peekable();
+
+    while let Some((position, c)) = chars.next() {
+        let token = match c {
+            ' ' | '\t' | '\n' | '\r' => continue,
+            '}' if text[position..].starts_with("}}") => return Ok((tokens, position)),
+            '.' => Token::Dot,
+            ',' => Token::Comma,
+            '|' if chars.next_if(|&(_, next)| next == '|').is_some() => Token::Or,
+            '|' => Token::Pipe,
+            '&' if chars.next_if(|&(_, next)| next == '&').is_some() => Token::And,
+            '+' => Token::Plus,
+            '/' => Token::Slash,
+            '%' => Token::Percent,
+            '<' if chars.next_if(|&(_, next)| next == '=').is_some() => Token::LessEqual,
+            '<' => Token::Less,
+            '>' if chars.next_if(|&(_, next)| next == '=').is_some() => Token::GreaterEqual,
+            '>' => Token::Greater,
+            '-' => Token::Minus,
+            '(' => Token::LParen,
+            ')' => Token::RParen,
+            '[' => Token::LBracket,
+            ']' => Token::RBracket,
+            '*' => Token::Star,
+            '?' if chars.next_if(|&(_, next)| next == '?').is_some() => Token::Coalesce,
+            '?' => Token::Question,
+            '=' if chars.next_if(|&(_, next)| next == '=').is_some() => Token::Equal,
+            '!' if chars.next_if(|&(_, next)| next == '=').is_some() => Token::NotEqual,
+            '!' => Token::Not,
+            '\'' | '"' => {
+                let mut value = String::new();
+                loop {
+                    match chars.next() {
+                        Some((_, '\\')) => match chars.next() {
+                            Some((_, 'n')) => value.push('\n'),
+                            Some((_, 't')) => value.push('\t'),
+                            Some((_, escaped)) => value.push(escaped),
+                            None => return Err(syntax_error(text, position, "unterminated string")),
+                        },
+                        Some((_, quote)) if quote == c => break,
+                        Some((_, other)) => value.push(other),
+                        None => return Err(syntax_error(text, position, "unterminated string")),
+                    }
+                }
+                Token::Str(value)
+            }
+            c if c.is_ascii_digit() => {
+                let mut number = c.to_string();
+                // after a dot a number is a path segment (azure.prefix.0.text), not a decimal
+                let after_dot = matches!(tokens.last(), Some((Token::Dot, _)));
+                while let Some(&(next_position, next)) = chars.peek() {
+                    let is_decimal_point = next == '.'
+                        && !after_dot
+                        && !number.contains('.')
+                        && text[next_position + 1..].starts_with(|d: char| d.is_ascii_digit());
+                    if next.is_ascii_digit() || is_decimal_point {
+                        number.push(next);
+                        chars.next();
+                    } else {
+                        break;
+                    }
+                }
+                let number = serde_yaml::from_str::<serde_yaml::Number>(&number)
+                    .map_err(|_| syntax_error(text, position, "invalid number"))?;
+                Token::Number(number)
+            }
+            c if c.is_alphabetic() || c == '_' => {
+                let mut ident = c.to_string();
+                while let Some(&(next_position, next)) = chars.peek() {
+                    let is_key_dash = next == '-'
+                        && text[next_position + 1..].starts_with(|d: char| d.is_alphanumeric() || d == '_');
+                    if next.is_alphanumeric() || next == '_' || is_key_dash {
+                        ident.push(next);
+                        chars.next();
+                    } else {
+                        break;
+                    }
+                }
+                Token::Ident(ident)
+            }
+            other => {
+                return Err(syntax_error(text, position, &format!("unexpected character '{}'", other)));
+            }
+        };
+        tokens.push((token, position));
+    }
+
+    if !placeholder {
+        return Ok((tokens, text.len()));
+    }
+    Err(syntax_error(text, start - 2, "missing closing }}"))
+}
+
+struct ExprParser<'t> {
+    text: &'t str,
+    tokens: Vec<(Token, usize)>,
+    index: usize,
+}
+
+impl ExprParser<'_> {
+    fn peek(&self) -> Option<&Token> {
+        self.tokens.get(self.index).map(|(token, _)| token)
+    }
+
+    fn position(&self) -> usize {
+        self.tokens
+            .get(self.index)
+            .map_or(self.text.len(), |(_, position)| *position)
+    }
+
+    fn next(&mut self) -> Option<Token> {
+        let token = self.tokens.get(self.index).map(|(token, _)| token.clone());
+        self.index += 1;
+        token
+    }
+
+    fn error(&self, message: &str) -> TemplateError {
+        syntax_error(self.text, self.position(), message)
+    }
+
+    fn expect(&mut self, expected: Token, description: &str) -> Result<(), TemplateError> {
+        if self.peek() == Some(&expected) {
+            self.index += 1;
+            Ok(())
+        } else {
+            Err(self.error(&format!("expected {}", description)))
+        }
+    }
+
+    fn expect_end(&self) -> Result<(), TemplateError> {
+        match self.peek() {
+            None => Ok(()),
+            Some(_) => Err(self.error("unexpected token")),
+        }
+    }
+
+    fn parse_expression(&mut self) -> Result<Expr, TemplateError> {
+        self.parse_binary(0)
+    }
+
+    // a word operator (e.g. not) unless it is the start of a path
+    fn peek_keyword(&self, keyword: &str) -> bool {
+        matches!(self.peek(), Some(Token::Ident(word)) if word == keyword)
+            && !matches!(
+                self.tokens.get(self.index + 1),
+                Some((Token::Dot, _)) | Some((Token::LBracket, _))
+            )
+    }
+
+    // the operator at the given precedence level, levels from the loosest
+    fn peek_binary_op(&self, level: usize) -> Option<BinaryOp> {
+        let op = match self.peek()? {
+            Token::Or => BinaryOp::Or,
+            Token::And => BinaryOp::And,
+            Token::Ident(word) if word == "or" => BinaryOp::Or,
+            Token::Ident(word) if word == "and" => BinaryOp::And,
+            Token::Equal => BinaryOp::Equal,
+            Token::NotEqual => BinaryOp::NotEqual,
+            Token::Less => BinaryOp::Less,
+            Token::LessEqual => BinaryOp::LessEqual,
+            Token::Greater => BinaryOp::Greater,
+            Token::GreaterEqual => BinaryOp::GreaterEqual,
+            Token::Plus => BinaryOp::Add,
+            Token::Minus => BinaryOp::Subtract,
+            Token::Star => BinaryOp::Multiply,
+            Token::Slash => BinaryOp::Divide,
+            Token::Percent => BinaryOp::Remainder,
+            _ => return None,
+        };
+        let op_level = match op {
+            BinaryOp::Or => 0,
+            BinaryOp::And => 1,
+            BinaryOp::Equal
+            | BinaryOp::NotEqual
+            | BinaryOp::Less
+            | BinaryOp::LessEqual
+            | BinaryOp::Greater
+            | BinaryOp::GreaterEqual => 3,
+            BinaryOp::Add | BinaryOp::Subtract => 4,
+            BinaryOp::Multiply | BinaryOp::Divide | BinaryOp::Remainder => 5,
+        };
+        (op_level == level).then_some(op)
+    }
+
+    // or (0), and (1), not (2), comparisons (3), + - (4), * / % (5), then the unary operators
+    fn parse_binary(&mut self, level: usize) -> Result<Expr, TemplateError> {
+        if level == 2 {
+            if self.peek() == Some(&Token::Not) || self.peek_keyword("not") {
+                self.index += 1;
+                let operand = self.parse_binary(2)?;
+                return Ok(Expr::Unary(UnaryOp::Not, Box::new(operand)));
+            }
+            return self.parse_binary(3);
+        }
+        if level > 5 {
+            return self.parse_unary();
+        }
+
+        let mut expr = self.parse_binary(level + 1)?;
+        while let Some(op) = self.peek_binary_op(level) {
+            self.index += 1;
+            let right = self.parse_binary(level + 1)?;
+            expr = Expr::Binary(op, Box::new(expr), Box::new(right));
+            // a == b == c is not allowed
+            if level == 3 && self.peek_binary_op(level).is_some() {
+                return Err(self.error("comparisons cannot be chained, use and"));
+            }
+        }
+        Ok(expr)
+    }
+
+    fn parse_unary(&mut self) -> Result<Expr, TemplateError> {
+        if self.peek() != Some(&Token::Minus) {
+            return self.parse_coalesce();
+        }
+        self.index += 1;
+        // a negative number is a literal, e.g. the index of items[-1]
+        if let Some(Token::Number(number)) = self.peek() {
+            let number = number.clone();
+            self.index += 1;
+            let negative = serde_yaml::from_str::<serde_yaml::Number>(&format!("-{}", number)).unwrap();
+            return Ok(Expr::Literal(Value::Number(negative)));
+        }
+        let operand = self.parse_unary()?;
+        Ok(Expr::Unary(UnaryOp::Negate, Box::new(operand)))
+    }
+
+    fn parse_coalesce(&mut self) -> Result<Expr, TemplateError> {
+        let mut expr = self.parse_pipe()?;
+        while self.peek() == Some(&Token::Coalesce) {
+            self.index += 1;
+            let fallback = self.parse_pipe()?;
+            expr = Expr::Coalesce(Box::new(expr), Box::new(fallback));
+        }
+        Ok(expr)
+    }
+
+    fn parse_pipe(&mut self) -> Result<Expr, TemplateError> {
+        let value = self.parse_primary()?;
+        let mut filters = Vec::new();
+        while self.peek() == Some(&Token::Pipe) {
+            self.index += 1;
+            let position = self.position();
+            let Some(Token::Ident(name)) = self.next() else {
+                self.index -= 1;
+                return Err(self.error("expected a filter name after |"));
+            };
+            let args = if self.peek() == Some(&Token::LParen) {
+                self.parse_arguments()?
+            } else {
+                Vec::new()
+            };
+            let column = column_of(self.text, position);
+            filters.push(Call { name, args, column });
+        }
+        if filters.is_empty() {
+            Ok(value)
+        } else {
+            Ok(Expr::Pipe(Box::new(value), filters))
+        }
+    }
+
+    fn parse_arguments(&mut self) -> Result<Vec<Expr>, TemplateError> {
+        self.expect(Token::LParen, "(")?;
+        let mut args = Vec::new();
+        if self.peek() == Some(&Token::RParen) {
+            self.index += 1;
+            return Ok(args);
+        }
+        loop {
+            args.push(self.parse_expression()?);
+            match self.next() {
+                Some(Token::Comma) => continue,
+                Some(Token::RParen) => return Ok(args),
+                _ => {
+                    self.index -= 1;
+                    return Err(self.error("expected , or )"));
+                }
+            }
+        }
+    }
+
+    fn parse_primary(&mut self) -> Result<Expr, TemplateError> {
+        let position = self.position();
+        match self.next() {
+            // {{ '{{' }} writes literal braces
+            Some(Token::Str(value)) => Ok(Expr::Literal(Value::String(value))),
+            Some(Token::Number(number)) => Ok(Expr::Literal(Value::Number(number))),
+            Some(Token::LParen) => {
+                let expr = self.parse_expression()?;
+                self.expect(Token::RParen, ")")?;
+                Ok(expr)
+            }
+            Some(Token::Ident(name)) => {
+                if self.peek() == Some(&Token::LParen) {
+                    let args = self.parse_arguments()?;
+                    let column = column_of(self.text, position);
+                    return Ok(Expr::Call(Call { name, args, column }));
+                }
+                let is_path = matches!(self.peek(), Some(Token::Dot) | Some(Token::LBracket));
+                match name.as_str() {
+                    "true" if !is_path => Ok(Expr::Literal(Value::Bool(true))),
+                    "false" if !is_path => Ok(Expr::Literal(Value::Bool(false))),
+                    "null" if !is_path => Ok(Expr::Literal(Value::Null)),
+                    _ => self.parse_path(vec![PathPart::Key(name)]).map(Expr::Path),
+                }
+            }
+            _ => {
+                self.index -= 1;
+                Err(syntax_error(self.text, position, "expected a value"))
+            }
+        }
+    }
+
+    // a path on its own (e.g. a --path argument), it can start with a quoted key, an index or *
+    fn parse_standalone_path(&mut self) -> Result<Vec<PathPart>, TemplateError> {
+        let first = match self.next() {
+            Some(Token::Ident(key)) | Some(Token::Str(key)) => PathPart::Key(key),
+            Some(Token::Number(index)) => PathPart::Key(index.to_string()),
+            Some(Token::Star) => PathPart::Wildcard,
+            Some(Token::LBracket) => {
+                self.index -= 1;
+                return self.parse_path(Vec::new());
+            }
+            _ => {
+                self.index -= 1;
+                return Err(self.error("expected a key"));
+            }
+        };
+        self.parse_path(vec![first])
+    }
+
+    fn parse_path(&mut self, mut parts: Vec<PathPart>) -> Result<Vec<PathPart>, TemplateError> {
+        loop {
+            match self.peek() {
+                Some(Token::Dot) => {
+                    self.index += 1;
+                    match self.next() {
+                        Some(Token::Ident(key)) | Some(Token::Str(key)) => parts.push(PathPart::Key(key)),
+                        Some(Token::Number(index)) => parts.push(PathPart::Key(index.to_string())),
+                        Some(Token::Star) => parts.push(PathPart::Wildcard),
+                        _ => {
+                            self.index -= 1;
+                            return Err(self.error("expected a key after ."));
+                        }
+                    }
+                }
+                Some(Token::LBracket) => {
+                    self.index += 1;
+                    let is_wildcard = self.peek() == Some(&Token::Star)
+                        && matches!(self.tokens.get(self.index + 1), Some((Token::RBracket, _)));
+                    if is_wildcard {
+                        self.index += 1;
+                        parts.push(PathPart::Wildcard);
+                    } else if self.peek() == Some(&Token::Question) {
+                        self.index += 1;
+                        parts.push(PathPart::Filter(self.parse_filter()?));
+                    } else {
+                        parts.push(PathPart::Index(Box::new(self.parse_expression()?)));
+                    }
+                    self.expect(Token::RBracket, "]")?;
+                }
+                _ => return Ok(parts),
+            }
+        }
+    }
+
+    // the part of [?field == value] after the ?, the field is a path inside the item
+    fn parse_filter(&mut self) -> Result<PathFilter, TemplateError> {
+        let mut field = Vec::new();
+        loop {
+            match self.next() {
+                Some(Token::Ident(key)) | Some(Token::Str(key)) => field.push(key),
+                Some(Token::Number(index)) if !field.is_empty() => field.push(index.to_string()),
+                _ => {
+                    self.index -= 1;
+                    return Err(self.error("expected a field in the filter"));
+                }
+            }
+            if self.peek() != Some(&Token::Dot) {
+                break;
+            }
+            self.index += 1;
+        }
+        let equal = match self.next() {
+            Some(Token::Equal) => true,
+            Some(Token::NotEqual) => false,
+            _ => {
+                self.index -= 1;
+                return Err(self.error("expected == or != in the filter"));
+            }
+        };
+        let value = self.parse_expression()?;
+        Ok(PathFilter {
+            field,
+            equal,
+            value: Box::new(value),
+        })
+    }
+}
+
+fn evaluate(expr: &Expr, context: &mut TemplateContext) -> Result<Value, TemplateError> {
+    match evaluate_optional(expr, context)? {
+        Some(value) => Ok(value),
+        None => {
+            // unknown keys are empty strings, reported as unresolved
+            context.record_unresolved(expr)?;
+            Ok(Value::String("".to_string()))
+        }
+    }
+}
+
+// None for a path that is not in the data, so a fallback can take its place
+fn evaluate_optional(expr: &Expr, context: &mut TemplateContext) -> Result<Option<Value>, TemplateError> {
+    match expr {
+        Expr::Literal(value) => Ok(Some(value.clone())),
+        Expr::Path(parts) => evaluate_path(parts, context),
+        // default(value, fallback) is value ?? fallback, a missing value is not unresolved
+        Expr::Call(call) if call.name == "default" && call.args.len() == 2 => {
+            match evaluate_optional(&call.args[0], context)? {
+                Some(value) if !value.is_null() => Ok(Some(value)),
+                _ => evaluate_optional(&call.args[1], context),
+            }
+        }
+        Expr::Call(call) => {
+            let params = call
+                .args
+                .iter()
+                .map(|arg| evaluate(arg, context))
+                .collect::<Result<Vec<_>, _>>()?;
+            call_function(&call.name, &params, context)
+                .map(Some)
+                .map_err(|e| e.at_column(call.column))
+        }
+        Expr::Pipe(value, filters) => {
+            let evaluated = evaluate_optional(value, context)?;
+            handle_pipe(evaluated, value, filters, context)
+        }
+        Expr::Coalesce(value, fallback) => match evaluate_optional(value, context)? {
+            Some(value) if !value.is_null() => Ok(Some(value)),
+            _ => evaluate_optional(fallback, context),
+        },
+        Expr::Unary(UnaryOp::Not, operand) => Ok(Some(Value::Bool(!is_truthy(&evaluate(operand, context)?)))),
+        Expr::Unary(UnaryOp::Negate, operand) => {
+            let value = evaluate(operand, context)?;
+            apply_binary(BinaryOp::Subtract, &Value::from(0), &value).map(Some)
+        }
+        // and, or only evaluate the right side when it decides the result
+        Expr::Binary(BinaryOp::And, left, right) => {
+            let result = is_truthy(&evaluate(left, context)?) && is_truthy(&evaluate(right, context)?);
+            Ok(Some(Value::Bool(result)))
+        }
+        Expr::Binary(BinaryOp::Or, left, right) => {
+            let result = is_truthy(&evaluate(left, context)?) || is_truthy(&evaluate(right, context)?);
+            Ok(Some(Value::Bool(result)))
+        }
+        Expr::Binary(op, left, right) => {
+            let left = evaluate(left, context)?;
+            let right = evaluate(right, context)?;
+            apply_binary(*op, &left, &right).map(Some)
+        }
+    }
+}
+
+// false for null, false, 0, an empty text and an empty list or map
+fn is_truthy(value: &Value) -> bool {
+    match value {
+        Value::Null => false,
+        Value::Bool(value) => *value,
+        Value::Number(number) => number.as_f64() != Some(0.0),
+        Value::String(text) => !text.is_empty(),
+        Value::Sequence(seq) => !seq.is_empty(),
+        Value::Mapping(map) => !map.is_empty(),
+        Value::Tagged(tagged) => is_truthy(&tagged.value),
+    }
+}
+
+#[derive(Debug, Clone, Copy)]
+enum Numeric {
+    Int(i64),
+    Float(f64),
+}
+
+impl Numeric {
+    fn as_f64(self) -> f64 {
+        match self {
+            Numeric::Int(value) => value as f64,
+            Numeric::Float(value) => value,
+        }
+    }
+}
+
+// a number, or a text holding one (e.g. iteration: '001')
+fn numeric_value(value: &Value) -> Option<Numeric> {
+    match value {
+        Value::Number(number) => match number.as_i64() {
+            Some(value) => Some(Numeric::Int(value)),
+            None => number.as_f64().map(Numeric::Float),
+        },
+        Value::String(text) => {
+            let text = text.trim();
+            match text.parse::<i64>() {
+                Ok(value) => Some(Numeric::Int(value)),
+                Err(_) => text.parse::<f64>().ok().filter(|value| value.is_finite()).map(Numeric::Float),
+            }
+        }
+        _ => None,
+    }
+}
+
+// numbers of both sides, when at least one side is a number and the other holds one
+fn numeric_pair(left: &Value, right: &Value) -> Option<(Numeric, Numeric)> {
+    if !left.is_number() && !right.is_number() {
+        return None;
+    }
+    Some((numeric_value(left)?, numeric_value(right)?))
+}
+
+fn arithmetic(op: BinaryOp, left: Numeric, right: Numeric) -> Result<Value, TemplateError> {
+    let is_zero = match right {
+        Numeric::Int(value) => value == 0,
+        Numeric::Float(value) => value == 0.0,
+    };
+    if is_zero && matches!(op, BinaryOp::Divide | BinaryOp::Remainder) {
+        return Err(TemplateError::new("Division by zero"));
+    }
+
+    // whole numbers stay whole when the result is one and fits
+    if let (Numeric::Int(left), Numeric::Int(right)) = (left, right) {
+        let result = match op {
+            BinaryOp::Add => left.checked_add(right),
+            BinaryOp::Subtract => left.checked_sub(right),
+            BinaryOp::Multiply => left.checked_mul(right),
+            BinaryOp::Divide if left % right == 0 => left.checked_div(right),
+            BinaryOp::Remainder => left.checked_rem(right),
+            _ => None,
+        };
+        if let Some(result) = result {
+            return Ok(Value::from(result));
+        }
+    }
+
+    let (left, right) = (left.as_f64(), right.as_f64());
+    let result = match op {
+        BinaryOp::Add => left + right,
+        BinaryOp::Subtract => left - right,
+        BinaryOp::Multiply => left * right,
+        BinaryOp::Divide => left / right,
+        _ => left % right,
+    };
+    Ok(Value::from(result))
+}
+
+fn apply_binary(op: BinaryOp, left: &Value, right: &Value) -> Result<Value, TemplateError> {
+    let invalid = || {
+        TemplateError::new(format!(
+            "Cannot apply '{}' to a {} and a {}",
+            op.symbol(),
+            value_type_name(left),
+            value_type_name(right)
+        ))
+    };
+
+    match op {
+        BinaryOp::Equal | BinaryOp::NotEqual => {
+            let equal = match numeric_pair(left, right) {
+                Some((l, r)) => l.as_f64() == r.as_f64(),
+                None => left == right,
+            };
+            Ok(Value::Bool(equal == (op == BinaryOp::Equal)))
+        }
+        BinaryOp::Less | BinaryOp::LessEqual | BinaryOp::Greater | BinaryOp::GreaterEqual => {
+            let ordering = match (numeric_pair(left, right), left, right) {
+                (Some((l, r)), _, _) => l.as_f64().partial_cmp(&r.as_f64()),
+                (None, Value::String(l), Value::String(r)) => Some(l.cmp(r)),
+                (None, Value::Bool(l), Value::Bool(r)) => Some(l.cmp(r)),
+                _ => None,
+            };
+            let ordering = ordering.ok_or_else(invalid)?;
+            let result = match op {
+                BinaryOp::Less => ordering.is_lt(),
+                BinaryOp::LessEqual => ordering.is_le(),
+                BinaryOp::Greater => ordering.is_gt(),
+                _ => ordering.is_ge(),
+            };
+            Ok(Value::Bool(result))
+        }
+        BinaryOp::Add => match (left, right) {
+            (Value::Sequence(l), Value::Sequence(r)) => Ok(Value::Sequence(l.iter().chain(r).cloned().collect())),
+            _ => match numeric_pair(left, right) {
+                Some((l, r)) => arithmetic(op, l, r),
+                // a text and a scalar are joined
+                None if left.is_string() || right.is_string() => {
+                    let scalar = |value: &Value| !matches!(value, Value::Sequence(_) | Value::Mapping(_));
+                    if !scalar(left) || !scalar(right) {
+                        return Err(invalid());
+                    }
+                    Ok(Value::String(value_to_text(left) + &value_to_text(right)))
                 }
-                return config_var;
+                None => Err(invalid()),
+            },
+        },
+        _ => {
+            let (l, r) = match (numeric_value(left), numeric_value(right)) {
+                (Some(l), Some(r)) => (l, r),
+                _ => return Err(invalid()),
+            };
+            arithmetic(op, l, r)
+        }
+    }
+}
+
+// the text of the path an expression refers to, for reporting
+fn reference_text(expr: &Expr) -> String {
+    match expr {
+        Expr::Path(parts) => path_text(parts),
+        Expr::Pipe(value, _) => reference_text(value),
+        Expr::Coalesce(_, fallback) => reference_text(fallback),
+        Expr::Literal(value) => value_to_text(value),
+        Expr::Call(call) => format!("{}(...)", call.name),
+        Expr::Unary(_, operand) => reference_text(operand),
+        Expr::Binary(op, left, right) => format!("{} {} {}", reference_text(left), op.symbol(), reference_text(right)),
+    }
+}
+
+// NAME of a reference to env.NAME
+fn env_variable_name(expr: &Expr, data: &Value) -> Option<String> {
+    match expr {
+        Expr::Path(parts) if data.get(ENV_NAMESPACE).is_none() => match parts.as_slice() {
+            [PathPart::Key(namespace), PathPart::Key(name), ..] if namespace == ENV_NAMESPACE => Some(name.clone()),
+            _ => None,
+        },
+        Expr::Pipe(value, _) => env_variable_name(value, data),
+        Expr::Coalesce(_, fallback) => env_variable_name(fallback, data),
+        _ => None,
+    }
+}
+
+fn path_text(parts: &[PathPart]) -> String {
+    let mut text = String::new();
+    for part in parts {
+        match part {
+            PathPart::Key(key) if text.is_empty() => text.push_str(key),
+            PathPart::Key(key) => {
+                text.push('.');
+                text.push_str(key);
+            }
+            PathPart::Index(index) => match &**index {
+                Expr::Literal(Value::String(key)) => text.push_str(&format!("['{}']", key)),
+                Expr::Literal(value) => text.push_str(&format!("[{}]", value_to_text(value))),
+                index => text.push_str(&format!("[{}]", reference_text(index))),
+            },
+            PathPart::Wildcard => text.push_str("[*]"),
+            PathPart::Filter(filter) => text.push_str(&format!(
+                "[?{} {} {}]",
+                filter.field.join("."),
+                if filter.equal { "==" } else { "!=" },
+                reference_text(&filter.value)
+            )),
+        }
+    }
+    text
+}
+
+fn evaluate_path(parts: &[PathPart], context: &mut TemplateContext) -> Result<Option<Value>, TemplateError> {
+    // a variable (e.g. a macro parameter) hides the data key of its name
+    if let Some(PathPart::Key(name)) = parts.first() {
+        if let Some(value) = context.scope_value(name).cloned() {
+            return select_rest(&value, &parts[1..], context);
+        }
+        // env.NAME, unless the data has an env key
+        if name == ENV_NAMESPACE && context.data.get(ENV_NAMESPACE).is_none() {
+            return match parts.get(1) {
+                Some(PathPart::Key(variable)) => match environment().get(variable)? {
+                    Some(value) => select_rest(&Value::from(value), &parts[2..], context),
+                    None => Ok(None),
+                },
+                _ => select_rest(&environment().to_value(), &parts[1..], context),
+            };
+        }
+    }
+
+    context.lookup(parts)
+}
+
+// the rest of a path, after the part looked up through the context
+fn select_rest(value: &Value, rest: &[PathPart], context: &mut TemplateContext) -> Result<Option<Value>, TemplateError> {
+    let selected = select_path(value, rest, &mut |index| evaluate(index, context))?;
+    if is_projection(rest) {
+        return Ok(Some(Value::Sequence(selected.into_iter().cloned().collect())));
+    }
+    Ok(selected.first().map(|value| (*value).clone()))
+}
+
+fn handle_pipe(
+    value: Option<Value>,
+    reference: &Expr,
+    filters: &[Call],
+    context: &mut TemplateContext,
+) -> Result<Option<Value>, TemplateError> {
+    // the value goes through the filters in order, each one gets it as its first parameter;
+    // default(fallback) takes the place of a missing or null value
+    let mut value = value;
+    for (position, filter) in filters.iter().enumerate() {
+        if filter.name == "default" {
+            if matches!(value, None | Some(Value::Null)) {
+                let Some(fallback) = filter.args.first() else {
+                    return Err(TemplateError::new("Function 'default' expects 1 parameter".to_string())
+                        .at_column(filter.column));
+                };
+                value = evaluate_optional(fallback, context)?;
+            }
+            continue;
+        }
+
+        let current = match value {
+            Some(value) => value,
+            // a missing value skips the filters up to its default
+            None if filters[position..].iter().any(|f| f.name == "default") => continue,
+            None => {
+                context.record_unresolved(reference)?;
+                Value::String("".to_string())
             }
+        };
+        let mut params = vec![current];
+        for arg in &filter.args {
+            params.push(evaluate(arg, context)?);
+        }
+        value = Some(call_function(&filter.name, &params, context).map_err(|e| e.at_column(filter.column))?);
+    }
+    Ok(value)
+}
 
-            if function_name == "lookup_config" {
-                // lookup_config('azure.prefix', 'resource_group')
-                let func_param_1 = modified_params.index(0);
-                let func_param_2 = modified_params.index(1);
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum ArgType {
+    Any,
+    // a scalar, used as its text
+    Text,
+    // a whole number or a text holding one
+    Integer,
+    List,
+}
+
+impl ArgType {
+    fn name(&self) -> &'static str {
+        match self {
+            ArgType::Any => "any",
+            ArgType::Text => "text",
+            ArgType::Integer => "integer",
+            ArgType::List => "list",
+        }
+    }
+
+    fn description(&self) -> &'static str {
+        match self {
+            ArgType::Any => "any value",
+            ArgType::Text => "a text",
+            ArgType::Integer => "an integer",
+            ArgType::List => "a list",
+        }
+    }
+
+    fn accepts(&self, value: &Value) -> bool {
+        match self {
+            ArgType::Any => true,
+            ArgType::Text => !matches!(value, Value::Sequence(_) | Value::Mapping(_)),
+            ArgType::Integer => integer_value(value).is_some(),
+            ArgType::List => value.is_sequence(),
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+struct FunctionParam {
+    name: String,
+    arg_type: ArgType,
+    optional: bool,
+    // the last parameter takes any number of values, at least one unless it is optional
+    variadic: bool,
+}
 
-                let config_variables = read_config_file();
-                //println!("config_variables: {:?}", config_variables);
-                let config_data = get_nested_value(&config_variables, func_param_1).unwrap();
-                //println!("config_data: {:?}", config_data);
+impl FunctionParam {
+    fn new(name: &str, arg_type: ArgType) -> Self {
+        FunctionParam {
+            name: name.to_string(),
+            arg_type,
+            optional: false,
+            variadic: false,
+        }
+    }
 
-                // config_data is a Value, so we need to convert it to a dictionary
-                let config_data = config_data.as_sequence().unwrap();
-                // find in the dictionary the key func_param_1 as "id" and return "text"
+    fn optional(mut self) -> Self {
+        self.optional = true;
+        self
+    }
 
-                let result = config_data
-                    .iter()
-                    .find(|x| x.get("id").and_then(|id| id.as_str()) == Some(func_param_2))
-                    .and_then(|x| x.get("text").and_then(|text| text.as_str()))
-                    .unwrap_or_else(|| {
-                        eprintln!("Error: func_param_2 '{}' not found", func_param_2);
-                        ""
-                    });
+    fn variadic(mut self) -> Self {
+        self.variadic = true;
+        self
+    }
+}
 
-                return result.to_string();
-            }
+impl std::fmt::Display for FunctionParam {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        let dots = if self.variadic { "..." } else { "" };
+        if self.optional {
+            write!(f, "[{}: {}{}]", self.name, self.arg_type.name(), dots)
+        } else {
+            write!(f, "{}: {}{}", self.name, self.arg_type.name(), dots)
+        }
+    }
+}
+
+// a function templates can call, as name(args) or as a filter (value | name(args)) where the
+// value is the first parameter; the registry checks the parameters before the call
+trait TemplateFunction {
+    fn name(&self) -> &str;
+    fn description(&self) -> &str;
+    fn params(&self) -> &[FunctionParam];
+    fn call(&self, args: &[Value], context: &mut TemplateContext) -> Result<Value, TemplateError>;
+}
+
+struct BuiltinFunction {
+    name: &'static str,
+    description: &'static str,
+    params: Vec<FunctionParam>,
+    call: fn(&[Value]) -> Result<Value, TemplateError>,
+}
+
+impl TemplateFunction for BuiltinFunction {
+    fn name(&self) -> &str {
+        self.name
+    }
+
+    fn description(&self) -> &str {
+        self.description
+    }
+
+    fn params(&self) -> &[FunctionParam] {
+        &self.params
+    }
+
+    fn call(&self, args: &[Value], _context: &mut TemplateContext) -> Result<Value, TemplateError> {
+        (self.call)(args)
+    }
+}
+
+// a builtin that reads the data being resolved
+struct DataFunction {
+    name: &'static str,
+    description: &'static str,
+    params: Vec<FunctionParam>,
+    call: fn(&[Value], &mut TemplateContext) -> Result<Value, TemplateError>,
+}
+
+impl TemplateFunction for DataFunction {
+    fn name(&self) -> &str {
+        self.name
+    }
+
+    fn description(&self) -> &str {
+        self.description
+    }
+
+    fn params(&self) -> &[FunctionParam] {
+        &self.params
+    }
+
+    fn call(&self, args: &[Value], context: &mut TemplateContext) -> Result<Value, TemplateError> {
+        (self.call)(args, context)
+    }
+}
+
+struct FunctionRegistry {
+    functions: Vec<Rc<dyn TemplateFunction>>,
+}
+
+impl Default for FunctionRegistry {
+    fn default() -> Self {
+        let mut registry = FunctionRegistry { functions: Vec::new() };
+        for function in builtin_functions() {
+            registry.register(Rc::new(function));
+        }
+        for function in data_functions() {
+            registry.register(Rc::new(function));
+        }
+        registry
+    }
+}
+
+impl FunctionRegistry {
+    // a function with the name of a registered one replaces it
+    fn register(&mut self, function: Rc<dyn TemplateFunction>) {
+        self.functions.retain(|registered| registered.name() != function.name());
+        self.functions.push(function);
+    }
+
+    fn get(&self, name: &str) -> Option<Rc<dyn TemplateFunction>> {
+        self.functions.iter().find(|function| function.name() == name).cloned()
+    }
+
+    fn signature(function: &dyn TemplateFunction) -> String {
+        let params: Vec<String> = function.params().iter().map(FunctionParam::to_string).collect();
+        format!("{}({})", function.name(), params.join(", "))
+    }
+
+    // the function to call with the arguments, once they match its parameters
+    fn checked(&self, name: &str, args: &[Value]) -> Result<Rc<dyn TemplateFunction>, TemplateError> {
+        let function = self
+            .get(name)
+            .ok_or_else(|| TemplateError::new(format!("Unknown function: {}", name)))?;
+        let params = function.params();
+
+        let required = params.iter().filter(|param| !param.optional).count();
+        let variadic = params.last().is_some_and(|param| param.variadic);
+        if args.len() < required || (!variadic && args.len() > params.len()) {
+            let expected = if variadic {
+                format!("at least {}", required)
+            } else if required == params.len() {
+                required.to_string()
+            } else {
+                format!("{} to {}", required, params.len())
+            };
+            return Err(TemplateError::new(format!(
+                "Function '{}' expects {} parameters, got {}: {}",
+                name,
+                expected,
+                args.len(),
+                FunctionRegistry::signature(function.as_ref())
+            )));
+        }
 
-            // if the function is get_data, get the date, this is an example of a function with parameters
-            if function_name == "get_data" {
-                let start_date = modified_params.index(0);
-                let end_date = modified_params.index(1);
-                return format!("{} - {}", start_date, end_date);
+        for (index, arg) in args.iter().enumerate() {
+            // the values of a variadic parameter all have its type
+            let param = &params[index.min(params.len() - 1)];
+            if !param.arg_type.accepts(arg) {
+                return Err(TemplateError::new(format!(
+                    "Function '{}' expects {} for '{}', got a {}",
+                    name,
+                    param.arg_type.description(),
+                    param.name,
+                    value_type_name(arg)
+                )));
             }
+        }
+        Ok(function)
+    }
+}
+
+// a macro declared in yaml, a template evaluated with its parameters as variables:
+//   _macros:
+//     resource_name:
+//       params: [prefix]
+//       template: "{{ prefix }}-{{ instance.stage }}-{{ instance.location_code }}"
+//     stage_upper: "{{ instance.stage | upper }}"
+struct TemplateMacro {
+    name: String,
+    description: String,
+    params: Vec<FunctionParam>,
+    template: String,
+}
 
-            if function_name == "concat" {
-                let start_str = modified_params.index(0);
-                let end_str = modified_params.index(1);
-                return format!("{}{}", start_str, end_str);
+impl TemplateMacro {
+    fn from_value(name: &str, definition: &Value) -> Result<Self, TemplateError> {
+        let invalid = |message: &str| TemplateError::new(format!("Invalid macro '{}': {}", name, message));
+
+        let (params, template, description) = match definition {
+            Value::String(template) => (Vec::new(), template.clone(), None),
+            Value::Mapping(_) => {
+                let params = match definition.get("params") {
+                    None => Vec::new(),
+                    Some(Value::Sequence(params)) => params
+                        .iter()
+                        .map(|param| param.as_str().map(|param| FunctionParam::new(param, ArgType::Any)))
+                        .collect::<Option<Vec<_>>>()
+                        .ok_or_else(|| invalid("params must be a list of names"))?,
+                    Some(_) => return Err(invalid("params must be a list of names")),
+                };
+                let template = definition
+                    .get("template")
+                    .and_then(Value::as_str)
+                    .ok_or_else(|| invalid("template must be a text"))?;
+                let description = definition.get("description").and_then(Value::as_str);
+                (params, template.to_string(), description.map(str::to_string))
             }
+            _ => return Err(invalid("expected a template or a map with params and template")),
+        };
+
+        // syntax errors show up when the macro is declared, not when it is first called
+        parse_template(&template).map_err(|e| invalid(&e.message))?;
+
+        Ok(TemplateMacro {
+            name: name.to_string(),
+            description: description.unwrap_or_else(|| format!("Macro: {}", template)),
+            params,
+            template,
+        })
+    }
+}
+
+impl TemplateFunction for TemplateMacro {
+    fn name(&self) -> &str {
+        &self.name
+    }
+
+    fn description(&self) -> &str {
+        &self.description
+    }
+
+    fn params(&self) -> &[FunctionParam] {
+        &self.params
+    }
+
+    fn call(&self, args: &[Value], context: &mut TemplateContext) -> Result<Value, TemplateError> {
+        if let Some(position) = context.macro_calls.iter().position(|name| *name == self.name) {
+            let mut chain = context.macro_calls[position..].to_vec();
+            chain.push(self.name.clone());
+            return Err(TemplateError::new(format!("Cycle in macros: {}", chain.join(" -> "))));
         }
 
-        // functions without parameters
+        // the template only sees its own parameters, not the variables of its caller
+        let scope = self
+            .params
+            .iter()
+            .zip(args)
+            .map(|(param, arg)| (param.name.clone(), arg.clone()))
+            .collect();
+        let scopes = std::mem::replace(&mut context.scopes, vec![scope]);
+        context.macro_calls.push(self.name.clone());
+        let result = context.resolve_typed(&self.template);
+        context.macro_calls.pop();
+        context.scopes = scopes;
+        result
+    }
+}
+
+fn call_function(name: &str, args: &[Value], context: &mut TemplateContext) -> Result<Value, TemplateError> {
+    // an unknown function is an empty string, reported as unresolved like an unknown key
+    if context.functions.get(name).is_none() {
+        context.record_unresolved_text(format!("{}()", name));
+        return Ok(Value::String("".to_string()));
+    }
+    let function = context.functions.checked(name, args)?;
+    function.call(args, context)
+}
+
+// text of a parameter, empty for an optional parameter that is left out
+fn text_arg(args: &[Value], index: usize) -> String {
+    args.get(index).map(value_to_text).unwrap_or_default()
+}
+
+fn integer_value(value: &Value) -> Option<i64> {
+    match value {
+        Value::Number(number) => number.as_i64(),
+        Value::String(text) => text.trim().parse().ok(),
+        _ => None,
+    }
+}
+
+// whole number parameter, checked by the registry, or the default when it is left out
+fn integer_arg(args: &[Value], index: usize, default: i64) -> i64 {
+    args.get(index).and_then(integer_value).unwrap_or(default)
+}
+
+// each seed is hashed with its length, so ('x-y') and ('x', 'y') give different digests
+fn seeds_digest(args: &[Value]) -> Vec<u8> {
+    use sha2::Digest;
+    let mut hasher = sha2::Sha256::new();
+    for seed in args.iter().map(value_to_text) {
+        hasher.update((seed.len() as u64).to_be_bytes());
+        hasher.update(seed.as_bytes());
+    }
+    hasher.finalize().to_vec()
+}
+
+// 13 lower case characters derived from the seeds, in the style of the ARM uniqueString:
+// the same seeds always give the same text
+fn unique_string(args: &[Value]) -> String {
+    const ALPHABET: &[u8] = b"abcdefghijklmnopqrstuvwxyz234567";
+    let digest = seeds_digest(args);
+    let mut hash = u64::from_be_bytes(digest[..8].try_into().unwrap());
+    let mut text = String::new();
+    for _ in 0..13 {
+        text.push(ALPHABET[(hash >> 59) as usize] as char);
+        hash <<= 5;
+    }
+    text
+}
+
+// current time of the date functions, the same for a whole run; --now sets it
+static NOW: std::sync::OnceLock<DateTime<Utc>> = std::sync::OnceLock::new();
+
+fn now() -> DateTime<Utc> {
+    *NOW.get_or_init(Utc::now)
+}
+
+// a date as RFC 3339 (2024-01-31T12:00:00+01:00), a date and time without offset (UTC),
+// a day (2024-01-31) or seconds since the epoch
+fn parse_date(value: &Value) -> Result<DateTime<FixedOffset>, TemplateError> {
+    let invalid = || TemplateError::new(format!("Invalid date: {}", value_to_text(value)));
+    let from_epoch = |seconds: i64| DateTime::from_timestamp(seconds, 0).map(|date| date.fixed_offset());
 
-        // if the function is get_date, get the date
-        if function_name == "get_date" {
-            return Utc::now().to_rfc3339();
+    let text = match value {
+        Value::Number(number) => return number.as_i64().and_then(from_epoch).ok_or_else(invalid),
+        Value::String(text) => text.trim(),
+        _ => return Err(invalid()),
+    };
+    if let Ok(seconds) = text.parse::<i64>() {
+        return from_epoch(seconds).ok_or_else(invalid);
+    }
+    if let Ok(date) = DateTime::parse_from_rfc3339(text) {
+        return Ok(date);
+    }
+    for format in ["%Y-%m-%dT%H:%M:%S", "%Y-%m-%d %H:%M:%S", "%Y-%m-%dT%H:%M", "%Y-%m-%d %H:%M"] {
+        if let Ok(date) = NaiveDateTime::parse_from_str(text, format) {
+            return Ok(date.and_utc().fixed_offset());
         }
     }
+    NaiveDate::parse_from_str(text, "%Y-%m-%d")
+        .map(|date| date.and_time(NaiveTime::MIN).and_utc().fixed_offset())
+        .map_err(|_| invalid())
+}
 
-    return "".to_string();
+fn parse_timezone(name: &str) -> Result<chrono_tz::Tz, TemplateError> {
+    name.trim()
+        .parse::<chrono_tz::Tz>()
+        .map_err(|_| TemplateError::new(format!("Unknown time zone: {}", name)))
 }
 
-fn handle_pipe(pipe_statement: &str, merged_yaml: &Value) -> String {
-    // at the moment the output of a pipe is a string, but it should be a Value
-    let mut parts: Vec<&str> = pipe_statement.split('|').collect();
-    if parts.len() < 2 {
-        let result = apply_function(pipe_statement, merged_yaml);
-        //println!("END PIPE: {}", result);
-        return result;
+fn format_date(date: &DateTime<FixedOffset>, format: &str) -> Result<String, TemplateError> {
+    if format.is_empty() {
+        return Ok(date.to_rfc3339());
     }
+    let items: Vec<chrono::format::Item> = chrono::format::StrftimeItems::new(format).collect();
+    if items.contains(&chrono::format::Item::Error) {
+        return Err(TemplateError::new(format!("Invalid date format: {}", format)));
+    }
+    Ok(date.format_with_items(items.into_iter()).to_string())
+}
 
-    let value = parts[0].trim();
-    let value = if value.starts_with('\'') && value.ends_with('\'') {
-        value.trim_matches('\'')
+// a date, formatted, in a time zone when one is given
+fn date_text(date: DateTime<FixedOffset>, format: &str, timezone: &str) -> Result<Value, TemplateError> {
+    let date = if timezone.is_empty() {
+        date
     } else {
-        match get_nested_value(&merged_yaml, value) {
-            Some(yaml_value) => yaml_value.as_str().unwrap_or(value),
-            None => {
-                eprintln!("Value not found: {}", value);
-                value
+        date.with_timezone(&parse_timezone(timezone)?).fixed_offset()
+    };
+    format_date(&date, format).map(Value::String)
+}
+
+// regex_replace runs for every item of a $for loop, each pattern is compiled once
+fn cached_regex(pattern: &str) -> Result<regex::Regex, TemplateError> {
+    static PATTERNS: std::sync::OnceLock<std::sync::Mutex<HashMap<String, regex::Regex>>> = std::sync::OnceLock::new();
+    let mut patterns = PATTERNS.get_or_init(Default::default).lock().unwrap();
+    if let Some(regex) = patterns.get(pattern) {
+        return Ok(regex.clone());
+    }
+    let regex = regex::Regex::new(pattern)
+        .map_err(|e| TemplateError::new(format!("Invalid pattern '{}': {}", pattern, e)))?;
+    patterns.insert(pattern.to_string(), regex.clone());
+    Ok(regex)
+}
+
+// a duration as amounts with units, e.g. 7d, -2h, 1d12h, 1w, 30m, 45s, 3mo or 1y
+fn add_duration(date: DateTime<FixedOffset>, duration: &str) -> Result<DateTime<FixedOffset>, TemplateError> {
+    let invalid = || TemplateError::new(format!("Invalid duration '{}', expected e.g. 7d, -2h, 1d12h, 3mo or 1y", duration));
+    static PARTS: std::sync::OnceLock<regex::Regex> = std::sync::OnceLock::new();
+    let parts = PARTS.get_or_init(|| regex::Regex::new(r"([+-]?\d+)(mo|[ywdhms])").unwrap());
+
+    let text: String = duration.chars().filter(|c| !c.is_whitespace()).collect();
+    let mut matched = 0;
+    let mut date = date;
+    for part in parts.captures_iter(&text) {
+        matched += part[0].len();
+        let amount: i64 = part[1].parse().map_err(|_| invalid())?;
+        date = match &part[2] {
+            "y" | "mo" => {
+                let months = if &part[2] == "y" { amount * 12 } else { amount };
+                let shift = chrono::Months::new(u32::try_from(months.unsigned_abs()).map_err(|_| invalid())?);
+                if months < 0 {
+                    date.checked_sub_months(shift)
+                } else {
+                    date.checked_add_months(shift)
+                }
+            }
+            unit => {
+                let seconds = match unit {
+                    "w" => 7 * 24 * 3600,
+                    "d" => 24 * 3600,
+                    "h" => 3600,
+                    "m" => 60,
+                    _ => 1,
+                };
+                chrono::TimeDelta::try_seconds(amount * seconds).and_then(|delta| date.checked_add_signed(delta))
             }
         }
-    };
+        .ok_or_else(invalid)?;
+    }
+    if matched == 0 || matched != text.len() {
+        return Err(invalid());
+    }
+    Ok(date)
+}
 
-    let action = parts[1].trim();
-    parts.drain(0..2);
+// the days from start to end, both included
+fn date_range(start: &Value, end: &Value) -> Result<Value, TemplateError> {
+    let start = parse_date(start)?.date_naive();
+    let end = parse_date(end)?.date_naive();
+    if (end - start).num_days() > 100_000 {
+        return Err(TemplateError::new("Date range is too long"));
+    }
+    let days = start
+        .iter_days()
+        .take_while(|day| *day <= end)
+        .map(|day| Value::String(day.format("%Y-%m-%d").to_string()))
+        .collect();
+    Ok(Value::Sequence(days))
+}
+
+// a test of the text against the second parameter, e.g. starts_with
+fn text_test(args: &[Value], test: fn(&str, &str) -> bool) -> Result<Value, TemplateError> {
+    Ok(test(&text_arg(args, 0), &text_arg(args, 1)).into())
+}
 
-    // this is an assumption that the input params are always strings, but is should be a Value.
-    let function_statement = format!("{}('{}')", action, value);
-    let result = apply_function(&function_statement, merged_yaml);
+// lower case words of letters and digits joined by dashes, e.g. "My App_01" -> "my-app-01"
+fn slugify(text: &str) -> String {
+    text.split(|c: char| !c.is_alphanumeric())
+        .filter(|word| !word.is_empty())
+        .map(str::to_lowercase)
+        .collect::<Vec<_>>()
+        .join("-")
+}
 
-    if parts.is_empty() {
-        //println!("NO PIPE: {}", result);
-        return result;
+// first letter of every word in upper case, the rest in lower case
+fn title_case(text: &str) -> String {
+    let mut title = String::new();
+    let mut word_start = true;
+    for c in text.chars() {
+        if word_start {
+            title.extend(c.to_uppercase());
+        } else {
+            title.extend(c.to_lowercase());
+        }
+        word_start = !c.is_alphanumeric();
     }
+    title
+}
+
+// characters from start (negative from the end), length of them or up to the end
+fn substring(text: &str, start: i64, length: Option<i64>) -> String {
+    let chars: Vec<char> = text.chars().collect();
+    let len = chars.len() as i64;
+    let start = if start < 0 { (len + start).max(0) } else { start.min(len) };
+    let end = match length {
+        Some(length) => (start + length.max(0)).min(len),
+        None => len,
+    };
+    chars[start as usize..end as usize].iter().collect()
+}
+
+fn builtin_functions() -> Vec<BuiltinFunction> {
+    use ArgType::*;
+    vec![
+        BuiltinFunction {
+            name: "upper",
+            description: "Text in upper case",
+            params: vec![FunctionParam::new("text", Text)],
+            call: |args| Ok(text_arg(args, 0).to_uppercase().into()),
+        },
+        BuiltinFunction {
+            name: "lower",
+            description: "Text in lower case",
+            params: vec![FunctionParam::new("text", Text)],
+            call: |args| Ok(text_arg(args, 0).to_lowercase().into()),
+        },
+        BuiltinFunction {
+            name: "len",
+            description: "Number of items of a list or map, or of characters of a text",
+            params: vec![FunctionParam::new("value", Any)],
+            call: |args| match &args[0] {
+                Value::Sequence(seq) => Ok(seq.len().into()),
+                Value::Mapping(map) => Ok(map.len().into()),
+                value => Ok(value_to_text(value).chars().count().into()),
+            },
+        },
+        BuiltinFunction {
+            name: "first",
+            description: "First item of a list, e.g. of the values a path filter selects",
+            params: vec![FunctionParam::new("list", Any)],
+            call: |args| match &args[0] {
+                Value::Sequence(seq) => Ok(seq.first().cloned().unwrap_or(Value::Null)),
+                value => Ok(value.clone()),
+            },
+        },
+        BuiltinFunction {
+            name: "last",
+            description: "Last item of a list",
+            params: vec![FunctionParam::new("list", Any)],
+            call: |args| match &args[0] {
+                Value::Sequence(seq) => Ok(seq.last().cloned().unwrap_or(Value::Null)),
+                value => Ok(value.clone()),
+            },
+        },
+        BuiltinFunction {
+            name: "default",
+            description: "The value, or the fallback when the value is missing or null",
+            params: vec![FunctionParam::new("value", Any), FunctionParam::new("fallback", Any)],
+            call: |args| match &args[0] {
+                Value::Null => Ok(args[1].clone()),
+                value => Ok(value.clone()),
+            },
+        },
+        BuiltinFunction {
+            name: "is_empty",
+            description: "True for an empty text",
+            params: vec![FunctionParam::new("text", Text)],
+            call: |args| Ok(text_arg(args, 0).is_empty().into()),
+        },
+        BuiltinFunction {
+            name: "is_not_empty",
+            description: "True for a text that is not empty",
+            params: vec![FunctionParam::new("text", Text)],
+            call: |args| Ok((!text_arg(args, 0).is_empty()).into()),
+        },
+        BuiltinFunction {
+            name: "get_env",
+            description: "Deprecated, use env.NAME: value of an allowed environment variable, the default (or empty) when it is not set",
+            params: vec![FunctionParam::new("name", Text), FunctionParam::new("default", Any).optional()],
+            call: |args| {
+                let name = text_arg(args, 0);
+                print_warning!("get_env is deprecated, use env.{} instead", name);
+                match environment().get(&name)? {
+                    Some(value) => Ok(value.into()),
+                    None if args.len() > 1 => Ok(args[1].clone()),
+                    None => {
+                        print_warning!("Environment variable {} is not set, written as an empty string", name);
+                        Ok("".into())
+                    }
+                }
+            },
+        },
+        BuiltinFunction {
+            name: "get_data",
+            description: "Deprecated, the two texts joined with ' - ', use concat or date_range instead",
+            params: vec![FunctionParam::new("start", Text), FunctionParam::new("end", Text)],
+            call: |args| {
+                print_warning!("get_data is deprecated, use concat or date_range instead");
+                Ok(format!("{} - {}", text_arg(args, 0), text_arg(args, 1)).into())
+            },
+        },
+        BuiltinFunction {
+            name: "concat",
+            description: "The texts joined",
+            params: vec![FunctionParam::new("texts", Text).variadic()],
+            call: |args| Ok(args.iter().map(value_to_text).collect::<String>().into()),
+        },
+        BuiltinFunction {
+            name: "replace",
+            description: "Text with every occurrence of from replaced by to",
+            params: vec![
+                FunctionParam::new("text", Text),
+                FunctionParam::new("from", Text),
+                FunctionParam::new("to", Text),
+            ],
+            call: |args| Ok(text_arg(args, 0).replace(&text_arg(args, 1), &text_arg(args, 2)).into()),
+        },
+        BuiltinFunction {
+            name: "regex_replace",
+            description: "Text with every match of the pattern replaced, $1 is the first group",
+            params: vec![
+                FunctionParam::new("text", Text),
+                FunctionParam::new("pattern", Text),
+                FunctionParam::new("replacement", Text),
+            ],
+            call: |args| {
+                let regex = cached_regex(&text_arg(args, 1))?;
+                Ok(regex
+                    .replace_all(&text_arg(args, 0), text_arg(args, 2).as_str())
+                    .into_owned()
+                    .into())
+            },
+        },
+        BuiltinFunction {
+            name: "substr",
+            description: "Characters from start (negative from the end), length of them or up to the end",
+            params: vec![
+                FunctionParam::new("text", Text),
+                FunctionParam::new("start", Integer),
+                FunctionParam::new("length", Integer).optional(),
+            ],
+            call: |args| {
+                let length = args.get(2).and_then(integer_value);
+                Ok(substring(&text_arg(args, 0), integer_arg(args, 1, 0), length).into())
+            },
+        },
+        BuiltinFunction {
+            name: "trim",
+            description: "Text without the whitespace, or the given characters, at both ends",
+            params: vec![FunctionParam::new("text", Text), FunctionParam::new("chars", Text).optional()],
+            call: |args| {
+                let text = text_arg(args, 0);
+                let chars: Vec<char> = text_arg(args, 1).chars().collect();
+                if chars.is_empty() {
+                    Ok(text.trim().into())
+                } else {
+                    Ok(text.trim_matches(chars.as_slice()).into())
+                }
+            },
+        },
+        BuiltinFunction {
+            name: "pad",
+            description: "Text filled up to width with fill (default space) on the left, or on the right with side 'right'",
+            params: vec![
+                FunctionParam::new("text", Text),
+                FunctionParam::new("width", Integer),
+                FunctionParam::new("fill", Text).optional(),
+                FunctionParam::new("side", Text).optional(),
+            ],
+            call: |args| {
+                let text = text_arg(args, 0);
+                let width = usize::try_from(integer_arg(args, 1, 0)).unwrap_or(0);
+                let fill = text_arg(args, 2).chars().next().unwrap_or(' ');
+                let padding: String = std::iter::repeat_n(fill, width.saturating_sub(text.chars().count())).collect();
+                match text_arg(args, 3).as_str() {
+                    "" | "left" => Ok(format!("{}{}", padding, text).into()),
+                    "right" => Ok(format!("{}{}", text, padding).into()),
+                    side => Err(TemplateError::new(format!("Invalid pad side '{}', expected left or right", side))),
+                }
+            },
+        },
+        BuiltinFunction {
+            name: "split",
+            description: "List of the parts of the text between the separators",
+            params: vec![FunctionParam::new("text", Text), FunctionParam::new("separator", Text)],
+            call: |args| {
+                let text = text_arg(args, 0);
+                let separator = text_arg(args, 1);
+                let parts: Vec<Value> = if separator.is_empty() {
+                    text.chars().map(|c| Value::String(c.to_string())).collect()
+                } else {
+                    text.split(separator.as_str()).map(|part| Value::String(part.to_string())).collect()
+                };
+                Ok(Value::Sequence(parts))
+            },
+        },
+        BuiltinFunction {
+            name: "join",
+            description: "Texts of the items of a list joined by the separator",
+            params: vec![FunctionParam::new("list", List), FunctionParam::new("separator", Text).optional()],
+            call: |args| {
+                let items: Vec<String> = args[0].as_sequence().unwrap().iter().map(value_to_text).collect();
+                Ok(items.join(&text_arg(args, 1)).into())
+            },
+        },
+        BuiltinFunction {
+            name: "truncate",
+            description: "First length characters of the text",
+            params: vec![FunctionParam::new("text", Text), FunctionParam::new("length", Integer)],
+            call: |args| Ok(substring(&text_arg(args, 0), 0, Some(integer_arg(args, 1, 0))).into()),
+        },
+        BuiltinFunction {
+            name: "slugify",
+            description: "Lower case words of letters and digits joined by dashes",
+            params: vec![FunctionParam::new("text", Text)],
+            call: |args| Ok(slugify(&text_arg(args, 0)).into()),
+        },
+        BuiltinFunction {
+            name: "title",
+            description: "Text with the first letter of every word in upper case",
+            params: vec![FunctionParam::new("text", Text)],
+            call: |args| Ok(title_case(&text_arg(args, 0)).into()),
+        },
+        BuiltinFunction {
+            name: "starts_with",
+            description: "True when the text starts with the prefix",
+            params: vec![FunctionParam::new("text", Text), FunctionParam::new("prefix", Text)],
+            call: |args| text_test(args, |text, prefix| text.starts_with(prefix)),
+        },
+        BuiltinFunction {
+            name: "ends_with",
+            description: "True when the text ends with the suffix",
+            params: vec![FunctionParam::new("text", Text), FunctionParam::new("suffix", Text)],
+            call: |args| text_test(args, |text, suffix| text.ends_with(suffix)),
+        },
+        BuiltinFunction {
+            name: "sha256",
+            description: "SHA-256 hash of the text, in hex",
+            params: vec![FunctionParam::new("text", Text)],
+            call: |args| {
+                use sha2::Digest;
+                let digest = sha2::Sha256::digest(text_arg(args, 0).as_bytes());
+                Ok(format!("{:x}", digest).into())
+            },
+        },
+        BuiltinFunction {
+            name: "md5",
+            description: "MD5 hash of the text, in hex",
+            params: vec![FunctionParam::new("text", Text)],
+            call: |args| {
+                use md5::Digest;
+                let digest = md5::Md5::digest(text_arg(args, 0).as_bytes());
+                Ok(format!("{:x}", digest).into())
+            },
+        },
+        BuiltinFunction {
+            name: "base64_encode",
+            description: "Text in base64",
+            params: vec![FunctionParam::new("text", Text)],
+            call: |args| {
+                use base64::Engine;
+                Ok(base64::engine::general_purpose::STANDARD
+                    .encode(text_arg(args, 0))
+                    .into())
+            },
+        },
+        BuiltinFunction {
+            name: "base64_decode",
+            description: "Text of a base64 value",
+            params: vec![FunctionParam::new("text", Text)],
+            call: |args| {
+                use base64::Engine;
+                let text = text_arg(args, 0);
+                let bytes = base64::engine::general_purpose::STANDARD
+                    .decode(text.trim())
+                    .map_err(|e| TemplateError::new(format!("Invalid base64 '{}': {}", text, e)))?;
+                let decoded = String::from_utf8(bytes)
+                    .map_err(|_| TemplateError::new(format!("Base64 '{}' is not a text", text)))?;
+                Ok(decoded.into())
+            },
+        },
+        BuiltinFunction {
+            name: "url_encode",
+            description: "Text with the characters that are not allowed in a url percent-encoded",
+            params: vec![FunctionParam::new("text", Text)],
+            call: |args| Ok(urlencoding::encode(&text_arg(args, 0)).into_owned().into()),
+        },
+        BuiltinFunction {
+            name: "uuid_v4",
+            description: "UUID v4 derived from the seeds, the same seeds give the same id",
+            params: vec![FunctionParam::new("seeds", Text).variadic()],
+            call: |args| {
+                let bytes: [u8; 16] = seeds_digest(args)[..16].try_into().unwrap();
+                Ok(uuid::Builder::from_random_bytes(bytes).into_uuid().to_string().into())
+            },
+        },
+        BuiltinFunction {
+            name: "unique_string",
+            description: "13 characters derived from the seeds, the same seeds give the same text",
+            params: vec![FunctionParam::new("seeds", Text).variadic()],
+            call: |args| Ok(unique_string(args).into()),
+        },
+        BuiltinFunction {
+            name: "get_date",
+            description: "Current date and time in RFC 3339",
+            params: vec![],
+            call: |_| Ok(now().to_rfc3339().into()),
+        },
+        BuiltinFunction {
+            name: "now",
+            description: "Current date and time, formatted (strftime, default RFC 3339) in a time zone (default UTC)",
+            params: vec![
+                FunctionParam::new("format", Text).optional(),
+                FunctionParam::new("timezone", Text).optional(),
+            ],
+            call: |args| date_text(now().fixed_offset(), &text_arg(args, 0), &text_arg(args, 1)),
+        },
+        BuiltinFunction {
+            name: "date_format",
+            description: "Date formatted (strftime, default RFC 3339), in a time zone when one is given",
+            params: vec![
+                FunctionParam::new("date", Text),
+                FunctionParam::new("format", Text).optional(),
+                FunctionParam::new("timezone", Text).optional(),
+            ],
+            call: |args| date_text(parse_date(&args[0])?, &text_arg(args, 1), &text_arg(args, 2)),
+        },
+        BuiltinFunction {
+            name: "date_add",
+            description: "Date moved by a duration, e.g. 7d, -2h, 1d12h, 1w, 30m, 45s, 3mo or 1y",
+            params: vec![FunctionParam::new("date", Text), FunctionParam::new("duration", Text)],
+            call: |args| Ok(add_duration(parse_date(&args[0])?, &text_arg(args, 1))?.to_rfc3339().into()),
+        },
+        BuiltinFunction {
+            name: "to_timezone",
+            description: "Date in a time zone (e.g. Europe/Zurich), in RFC 3339",
+            params: vec![FunctionParam::new("date", Text), FunctionParam::new("timezone", Text)],
+            call: |args| date_text(parse_date(&args[0])?, "", &text_arg(args, 1)),
+        },
+        BuiltinFunction {
+            name: "to_epoch",
+            description: "Seconds since the epoch of a date",
+            params: vec![FunctionParam::new("date", Text)],
+            call: |args| Ok(parse_date(&args[0])?.timestamp().into()),
+        },
+        BuiltinFunction {
+            name: "from_epoch",
+            description: "Date of seconds since the epoch, in RFC 3339 UTC",
+            params: vec![FunctionParam::new("seconds", Integer)],
+            call: |args| Ok(parse_date(&args[0])?.to_rfc3339().into()),
+        },
+        BuiltinFunction {
+            name: "date_range",
+            description: "List of the days (YYYY-MM-DD) from start to end, both included",
+            params: vec![FunctionParam::new("start_date", Text), FunctionParam::new("end_date", Text)],
+            call: |args| date_range(&args[0], &args[1]),
+        },
+    ]
+}
+
+fn data_functions() -> Vec<DataFunction> {
+    use ArgType::*;
+    vec![
+        DataFunction {
+            name: "get_config",
+            description: "Value of a key of the config, its placeholders resolved",
+            params: vec![FunctionParam::new("key", Text)],
+            call: |args, context| {
+                let func_param_1 = text_arg(args, 0);
+
+                let config_variables = config_value()?;
+                let config_var = match config_variables.get(&func_param_1) {
+                    Some(value) => {
+                        let path = format!("config:{}", func_param_1);
+                        value_to_text(&context.resolve_referenced(value, &path)?)
+                    }
+                    None => {
+                        print_warning!("Config key {} not found, written as an empty string", func_param_1);
+                        String::new()
+                    }
+                };
+                Ok(config_var.into())
+            },
+        },
+        DataFunction {
+            name: "lookup_config",
+            description: "Text of the item with the given id in a list of the config, its placeholders resolved",
+            params: vec![FunctionParam::new("list", Text), FunctionParam::new("id", Text)],
+            call: |args, context| {
+                // lookup_config('azure.prefix', 'resource_group')
+                let list = text_arg(args, 0);
+                let items = query_nested_value(config_value()?, &list)?
+                    .ok_or_else(|| TemplateError::new(format!("Config list not found: {}", list)))?;
+                let text = lookup_item(&format!("config:{}", list), &items, "id", &args[1], Some("text"))?;
+                let path = format!("config:{}[?id=='{}'].text", list, text_arg(args, 1));
+                context.resolve_referenced(&text, &path)
+            },
+        },
+        DataFunction {
+            name: "lookup",
+            description: "The item of a list whose match_field equals match_value, or its return_field; the list is a path of the data, config:PATH or file:FILE#PATH",
+            params: vec![
+                FunctionParam::new("source", Text),
+                FunctionParam::new("match_field", Text),
+                FunctionParam::new("match_value", Any),
+                FunctionParam::new("return_field", Text).optional(),
+            ],
+            call: |args, context| {
+                // lookup('azure.prefix', 'id', 'storage_account', 'text')
+                let source = text_arg(args, 0);
+                let items = lookup_source(&source, context)?;
+                let return_field = args.get(3).map(value_to_text);
+                lookup_item(&source, &items, &text_arg(args, 1), &args[2], return_field.as_deref())
+            },
+        },
+    ]
+}
+
+// the list a lookup searches: a path of the data (its placeholders resolved), config:PATH
+// of the config or file:FILE#PATH of a data file, as it is
+fn lookup_source(source: &str, context: &mut TemplateContext) -> Result<Value, TemplateError> {
+    let value = if let Some(path) = source.strip_prefix("config:") {
+        query_nested_value(config_value()?, path)?
+    } else if let Some(file) = source.strip_prefix("file:") {
+        let (file, path) = file.split_once('#').unwrap_or((file, ""));
+        let data = context.data_file(file)?;
+        if path.is_empty() {
+            Some(data)
+        } else {
+            query_nested_value(&data, path)?
+        }
+    } else {
+        evaluate_path(&parse_value_path(source)?, context)?
+    };
+    value.ok_or_else(|| TemplateError::new(format!("Lookup source not found: {}", source)))
+}
 
-    let next_pipe = parts.join("|");
-    let new_pipe_statement = format!("{}|{}", result, next_pipe);
+// the first item (of a list, or value of a map) whose match_field equals match_value, or its
+// return_field with its own type
+fn lookup_item(
+    source: &str,
+    items: &Value,
+    match_field: &str,
+    match_value: &Value,
+    return_field: Option<&str>,
+) -> Result<Value, TemplateError> {
+    let items: Vec<&Value> = match items {
+        Value::Sequence(seq) => seq.iter().collect(),
+        Value::Mapping(map) => map.values().collect(),
+        other => {
+            return Err(TemplateError::new(format!(
+                "Lookup source {} is a {}, expected a list or a map",
+                source,
+                value_type_name(other)
+            )))
+        }
+    };
 
-    //println!("NEW PIPE: {}", new_pipe_statement);
+    let mut found = None;
+    for item in items {
+        let Some(field) = query_nested_value(item, match_field)? else {
+            continue;
+        };
+        if apply_binary(BinaryOp::Equal, &field, match_value)? == Value::Bool(true) {
+            found = Some(item);
+            break;
+        }
+    }
+    let criteria = format!("{} == '{}'", match_field, value_to_text(match_value));
+    let item = found.ok_or_else(|| TemplateError::new(format!("No item with {} in {}", criteria, source)))?;
 
-    handle_pipe(&new_pipe_statement, merged_yaml)
+    let Some(return_field) = return_field else {
+        return Ok(item.clone());
+    };
+    query_nested_value(item, return_field)?.ok_or_else(|| {
+        TemplateError::new(format!(
+            "The item with {} in {} has no field '{}'",
+            criteria, source, return_field
+        ))
+    })
 }
 
 fn save_to_file(output_path: &Path, output_yaml: &String) {
@@ -1326,23 +5256,345 @@ fn save_to_file(output_path: &Path, output_yaml: &String) {
 }
 
 // need to transform a string "root.level1.name: 'demo'" a Value
-fn set_nested_value<'a>(yaml_value: &'a mut Value, path: &str, value: Value) {
-    if yaml_value.is_null() {
-        *yaml_value = Value::Mapping(serde_yaml::Mapping::new());
+// sets the value at a path, missing keys are created as maps; with a wildcard or a filter
+// every selected item is set
+fn set_nested_value(yaml_value: &mut Value, path: &str, value: Value) {
+    // task names are not always valid paths (e.g. `run build`), those are split on the dots
+    let parts = parse_value_path(path)
+        .unwrap_or_else(|_| path.split('.').map(|key| PathPart::Key(key.to_string())).collect());
+    let Some((last, parents)) = parts.split_last() else {
+        return;
+    };
+
+    let mut updated = false;
+    for parent in select_path_mut(yaml_value, parents, true) {
+        updated |= set_child_value(parent, last, &value);
     }
-    let mut current_value = yaml_value;
+    if !updated {
+        print_warning!("Could not set the value at {}", path);
+    }
+}
 
-    let mut keys = path.split('.');
-    let last_key = keys.next_back().unwrap();
-    for key in keys {
-        current_value = current_value
-            .as_mapping_mut()
-            .unwrap()
-            .entry(Value::String(key.to_string()))
-            .or_insert(Value::Mapping(serde_yaml::Mapping::new()));
+fn remove_nested_value(yaml_value: &mut Value, path: &str) -> Option<Value> {
+    let parts = parse_value_path(path).ok()?;
+    let (last, parents) = parts.split_last()?;
+
+    let mut removed = Vec::new();
+    for parent in select_path_mut(yaml_value, parents, false) {
+        removed.extend(remove_child_value(parent, last));
+    }
+    if is_projection(&parts) {
+        return Some(Value::Sequence(removed));
+    }
+    removed.into_iter().next()
+}
+
+// the values a path selects, for changing them; with create missing keys are added
+fn select_path_mut<'v>(value: &'v mut Value, parts: &[PathPart], create: bool) -> Vec<&'v mut Value> {
+    let Some((part, rest)) = parts.split_first() else {
+        return vec![value];
+    };
+
+    let children: Vec<&mut Value> = match part {
+        PathPart::Key(key) if create && matches!(value, Value::Null | Value::Mapping(_)) => {
+            if value.is_null() {
+                *value = Value::Mapping(serde_yaml::Mapping::new());
+            }
+            let map = value.as_mapping_mut().unwrap();
+            vec![map.entry(Value::String(key.clone())).or_insert(Value::Null)]
+        }
+        PathPart::Key(key) => child_value_mut(value, &Value::String(key.clone())).into_iter().collect(),
+        PathPart::Index(index) => match literal_value(index) {
+            Ok(index) => child_value_mut(value, &index).into_iter().collect(),
+            Err(_) => Vec::new(),
+        },
+        PathPart::Wildcard => child_values_mut(value),
+        PathPart::Filter(filter) => match literal_value(&filter.value) {
+            Ok(expected) => child_values_mut(value)
+                .into_iter()
+                .filter(|item| filter.matches(item, &expected))
+                .collect(),
+            Err(_) => Vec::new(),
+        },
+    };
+    children
+        .into_iter()
+        .flat_map(|child| select_path_mut(child, rest, create))
+        .collect()
+}
+
+fn child_value_mut<'v>(value: &'v mut Value, index: &Value) -> Option<&'v mut Value> {
+    match (value, index) {
+        (Value::Sequence(seq), Value::Number(number)) => {
+            let position = number.as_i64().and_then(|i| sequence_index(seq.len(), i))?;
+            seq.get_mut(position)
+        }
+        (Value::Sequence(seq), Value::String(key)) => {
+            let position = key.parse::<i64>().ok().and_then(|i| sequence_index(seq.len(), i))?;
+            seq.get_mut(position)
+        }
+        (Value::Mapping(map), index) => map.get_mut(index),
+        (Value::Tagged(tagged), index) => child_value_mut(&mut tagged.value, index),
+        _ => None,
+    }
+}
+
+fn child_values_mut(value: &mut Value) -> Vec<&mut Value> {
+    match value {
+        Value::Sequence(seq) => seq.iter_mut().collect(),
+        Value::Mapping(map) => map.values_mut().collect(),
+        Value::Tagged(tagged) => child_values_mut(&mut tagged.value),
+        _ => Vec::new(),
+    }
+}
+
+fn set_child_value(parent: &mut Value, part: &PathPart, value: &Value) -> bool {
+    let index = match part {
+        PathPart::Key(key) => Value::String(key.clone()),
+        PathPart::Index(index) => match literal_value(index) {
+            Ok(index) => index,
+            Err(_) => return false,
+        },
+        PathPart::Wildcard | PathPart::Filter(_) => {
+            let selected = select_path_mut(parent, std::slice::from_ref(part), false);
+            let updated = !selected.is_empty();
+            for item in selected {
+                *item = value.clone();
+            }
+            return updated;
+        }
+    };
+
+    if parent.is_null() && index.is_string() {
+        *parent = Value::Mapping(serde_yaml::Mapping::new());
+    }
+    if let Value::Mapping(map) = parent {
+        map.insert(index, value.clone());
+        return true;
+    }
+    match child_value_mut(parent, &index) {
+        Some(item) => {
+            *item = value.clone();
+            true
+        }
+        None => false,
+    }
+}
+
+fn remove_child_value(parent: &mut Value, part: &PathPart) -> Vec<Value> {
+    let index = match part {
+        PathPart::Key(key) => Value::String(key.clone()),
+        PathPart::Index(index) => match literal_value(index) {
+            Ok(index) => index,
+            Err(_) => return Vec::new(),
+        },
+        PathPart::Wildcard => match parent {
+            Value::Sequence(seq) => return std::mem::take(seq),
+            Value::Mapping(map) => return std::mem::take(map).into_iter().map(|(_, value)| value).collect(),
+            _ => return Vec::new(),
+        },
+        PathPart::Filter(filter) => {
+            let Ok(expected) = literal_value(&filter.value) else {
+                return Vec::new();
+            };
+            let Value::Sequence(seq) = parent else {
+                return Vec::new();
+            };
+            let (removed, kept) = std::mem::take(seq)
+                .into_iter()
+                .partition(|item| filter.matches(item, &expected));
+            *seq = kept;
+            return removed;
+        }
+    };
+
+    match (parent, &index) {
+        (Value::Mapping(map), index) => map.shift_remove(index).into_iter().collect(),
+        (Value::Sequence(seq), Value::Number(number)) => number
+            .as_i64()
+            .and_then(|i| sequence_index(seq.len(), i))
+            .map(|i| seq.remove(i))
+            .into_iter()
+            .collect(),
+        (Value::Sequence(seq), Value::String(key)) => key
+            .parse::<i64>()
+            .ok()
+            .and_then(|i| sequence_index(seq.len(), i))
+            .map(|i| seq.remove(i))
+            .into_iter()
+            .collect(),
+        _ => Vec::new(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn yaml(text: &str) -> Value {
+        serde_yaml::from_str(text).unwrap()
+    }
+
+    fn resolve(data: &str, template: &str) -> Result<Value, TemplateError> {
+        let data = yaml(data);
+        TemplateContext::new(&data).resolve_typed(template)
+    }
+
+    fn merged(base: &str, overlay: &str, options: &MergeOptions) -> Value {
+        let mut base = yaml(base);
+        merge_yaml(&mut base, &yaml(overlay), "", options);
+        base
+    }
+
+    fn syntax_error_of(template: &str) -> String {
+        resolve("{}", template).unwrap_err().to_string()
+    }
+
+    #[test]
+    fn list_strategies_merge_lists() {
+        let base = "tags: [a, b]";
+        let overlay = "tags: [b, c]";
+        let with = |list_strategy| MergeOptions {
+            list_strategy,
+            ..MergeOptions::default()
+        };
+        assert_eq!(merged(base, overlay, &with(ListStrategy::AppendUnique)), yaml("tags: [a, b, c]"));
+        assert_eq!(merged(base, overlay, &with(ListStrategy::Replace)), yaml("tags: [b, c]"));
+        assert_eq!(merged(base, overlay, &with(ListStrategy::Prepend)), yaml("tags: [c, a, b]"));
+    }
+
+    #[test]
+    fn prepend_strategy_skips_duplicates_unlike_the_tag() {
+        let options = MergeOptions {
+            list_strategy: ListStrategy::Prepend,
+            ..MergeOptions::default()
+        };
+        assert_eq!(merged("tags: [a, b]", "tags: [b, c]", &options), yaml("tags: [c, a, b]"));
+        assert_eq!(merged("tags: [a, b]", "tags: !prepend [b, c]", &options), yaml("tags: [b, c, a, b]"));
+    }
+
+    #[test]
+    fn merge_by_key_merges_matching_items() {
+        let base = "prefix: [{id: rg, text: rg}, {id: sa, text: sa}]";
+        let overlay = "prefix: [{id: sa, text: st}, {id: kv, text: kv}]";
+        let options = MergeOptions {
+            list_strategy: ListStrategy::MergeByKey,
+            ..MergeOptions::default()
+        };
+        assert_eq!(
+            merged(base, overlay, &options),
+            yaml("prefix: [{id: rg, text: rg}, {id: sa, text: st}, {id: kv, text: kv}]")
+        );
+    }
+
+    #[test]
+    fn tag_directives_override_the_list_strategy() {
+        let base = "tags: [a, b]\nowner: team\nsku: {name: basic, tier: 1}";
+        let options = MergeOptions::default();
+        assert_eq!(merged(base, "tags: !replace [c]", &options)["tags"], yaml("[c]"));
+        // unlike the strategies, !append and !prepend keep duplicates
+        assert_eq!(merged(base, "tags: !append [a]", &options)["tags"], yaml("[a, b, a]"));
+        assert_eq!(merged(base, "tags: !prepend [b]", &options)["tags"], yaml("[b, a, b]"));
+        assert_eq!(merged(base, "sku: !replace {name: premium}", &options)["sku"], yaml("{name: premium}"));
+        assert!(merged(base, "owner: !delete", &options).get("owner").is_none());
+    }
+
+    #[test]
+    fn merge_section_directives_apply_to_their_document() {
+        let path = std::env::temp_dir().join("yw_merge_section_directives.yaml");
+        fs::write(&path, "_merge:\n  lists:\n    tags: replace\n  delete: [old]\ntags: [c]\nnew: null\n").unwrap();
+        let mut merged_yaml = yaml("tags: [a, b]\nold: {x: 1}");
+        merge_yaml_file(&path, &mut merged_yaml, &MergeOptions::default(), &mut MergeTracker::default());
+        fs::remove_file(&path).unwrap();
+        assert_eq!(merged_yaml, yaml("tags: [c]\nnew: null"));
+    }
+
+    #[test]
+    fn tokenize_reports_the_column_of_syntax_errors() {
+        assert!(syntax_error_of("{{ 'open }}").starts_with("Syntax error at column 4: unterminated string"));
+        assert!(syntax_error_of("{{ a # b }}").starts_with("Syntax error at column 6: unexpected character '#'"));
+        assert!(syntax_error_of("x {{ a").contains("missing closing }}"));
+    }
+
+    #[test]
+    fn parse_rejects_incomplete_expressions() {
+        for template in ["{{ upper( }}", "{{ a + }}", "{{ a b }}", "{{ concat('a',, 'b') }}", "{{ a[0 }}"] {
+            assert!(syntax_error_of(template).starts_with("Syntax error at column"), "{}", template);
+        }
+    }
+
+    #[test]
+    fn missing_paths_resolve_to_nothing_without_their_parent() {
+        let data = "a: {x: '{{ a.y }}', z: '{{ a.x }}'}";
+        assert_eq!(resolve(data, "{{ a.z }}").unwrap(), yaml("''"));
+        assert_eq!(resolve(data, "{{ a.y.deeper }}").unwrap(), yaml("''"));
+    }
+
+    #[test]
+    fn fallbacks_apply_to_missing_siblings() {
+        let data = "a: {x: \"{{ a.y ?? 'fallback' }}\", z: \"{{ default(a.y, 'fb') }}\"}";
+        assert_eq!(resolve(data, "{{ a.x }} {{ a.z }}").unwrap(), yaml("fallback fb"));
+        let mut context = TemplateContext::new(&Value::Null);
+        assert_eq!(context.resolve_typed("{{ default(other.missing, 'fb') }}").unwrap(), yaml("fb"));
+        assert!(context.unresolved.is_empty());
+    }
+
+    #[test]
+    fn list_items_refer_to_items_of_their_list() {
+        let data = "items: ['{{ items[1] }}', b, '{{ items.0 }}-c']";
+        assert_eq!(resolve(data, "{{ items }}").unwrap(), yaml("[b, b, b-c]"));
+    }
+
+    #[test]
+    fn quoted_keys_are_not_nested_paths() {
+        let data = "c: {'a.b': '{{ x }}', a: {b: '{{ y }}'}}\nx: quoted\ny: nested";
+        assert_eq!(resolve(data, "{{ c.a.b }} {{ c['a.b'] }}").unwrap(), yaml("nested quoted"));
+        assert_eq!(resolve(data, "{{ c['a.b'] }} {{ c.a.b }}").unwrap(), yaml("quoted nested"));
+    }
+
+    #[test]
+    fn path_filters_select_and_remove_items() {
+        let data = yaml("prefix: [{id: rg, text: rg}, {id: sa, text: sa}, {id: kv, text: kv}]");
+        let query = |path: &str| query_nested_value(&data, path).unwrap();
+        assert_eq!(query("prefix[?id=='sa'].text"), Some(yaml("[sa]")));
+        assert_eq!(query("prefix[?id!='sa'].id"), Some(yaml("[rg, kv]")));
+        assert_eq!(query("prefix[-1].text"), Some(yaml("kv")));
+        assert_eq!(query("prefix[?id=='none'].text"), Some(yaml("[]")));
+
+        let mut data = data.clone();
+        remove_nested_value(&mut data, "prefix[?id=='rg']");
+        assert_eq!(data, yaml("prefix: [{id: sa, text: sa}, {id: kv, text: kv}]"));
+    }
+
+    #[test]
+    fn operators_bind_by_precedence() {
+        let data = "stage: dev\nn: 3";
+        assert_eq!(resolve(data, "{{ 1 + 2 * 3 }}").unwrap(), yaml("7"));
+        assert_eq!(resolve(data, "{{ (1 + 2) * 3 }}").unwrap(), yaml("9"));
+        assert_eq!(resolve(data, "{{ 10 - 4 - 3 }}").unwrap(), yaml("3"));
+        assert_eq!(resolve(data, "{{ -n + 1 }}").unwrap(), yaml("-2"));
+        assert_eq!(resolve(data, "{{ true or false and false }}").unwrap(), yaml("true"));
+        assert_eq!(resolve(data, "{{ not n == 3 }}").unwrap(), yaml("false"));
+        assert_eq!(resolve(data, "{{ stage | upper == 'DEV' }}").unwrap(), yaml("true"));
+        assert_eq!(resolve(data, "{{ missing ?? 2 + 1 }}").unwrap(), yaml("3"));
+    }
+
+    #[test]
+    fn dashes_are_part_of_keys_and_spaced_minus_subtracts() {
+        let data = "out-1: {name: web}\nn: 5";
+        assert_eq!(resolve(data, "{{ out-1.name }}").unwrap(), yaml("web"));
+        assert_eq!(resolve(data, "{{ n - 1 }}").unwrap(), yaml("4"));
+    }
+
+    #[test]
+    fn len_counts_characters() {
+        assert_eq!(resolve("city: zürich", "{{ len(city) }}").unwrap(), yaml("6"));
+        assert_eq!(resolve("tags: [a, b]", "{{ tags | len }}").unwrap(), yaml("2"));
+    }
+
+    #[test]
+    fn string_literals_keep_their_braces_for_functions() {
+        assert_eq!(resolve("{}", "{{ len('{{') }}").unwrap(), yaml("2"));
+        assert_eq!(resolve("{}", "{{ '{{' }}.Names{{ '}}' }}").unwrap(), yaml("'{{.Names}}'"));
     }
-    current_value
-        .as_mapping_mut()
-        .unwrap()
-        .insert(Value::String(last_key.to_string()), value);
 }
@@ -0,0 +1,104 @@
+// runs the `## cargo run -- ...` header of each fixture in tests/dir3 and compares what it
+// writes (--output FILE, or > FILE for the printed value) with the file committed under
+// tests/output; a fixture with a `## fails with: TEXT` second line has to fail with TEXT in
+// its errors (e.g. a placeholder cycle)
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::process::Command;
+
+// the arguments of a header, double quoted ones can hold spaces
+fn header_args(header: &str) -> Vec<String> {
+    let mut args = Vec::new();
+    let mut arg = String::new();
+    let mut quoted = false;
+    for c in header.chars() {
+        match c {
+            '"' => quoted = !quoted,
+            ' ' if !quoted => {
+                if !arg.is_empty() {
+                    args.push(std::mem::take(&mut arg));
+                }
+            }
+            _ => arg.push(c),
+        }
+    }
+    if !arg.is_empty() {
+        args.push(arg);
+    }
+    args
+}
+
+// the fixture run with its output moved to the target directory: the expected file, and the
+// file the run wrote (the errors when it failed)
+fn run_fixture(fixture: &Path, header: &str) -> (PathBuf, Result<String, String>) {
+    let mut args = header_args(header);
+    let actual = Path::new(env!("CARGO_TARGET_TMPDIR")).join(fixture.file_name().unwrap());
+
+    let redirect = args.iter().position(|arg| arg == ">");
+    let expected = match redirect {
+        Some(position) => {
+            let expected = args.split_off(position)[1].clone();
+            PathBuf::from(expected)
+        }
+        None => {
+            let position = args.iter().position(|arg| arg == "--output").expect("header without --output");
+            let expected = std::mem::replace(&mut args[position + 1], actual.display().to_string());
+            PathBuf::from(expected)
+        }
+    };
+
+    // no user config and only the variables the fixture sets
+    let output = Command::new(env!("CARGO_BIN_EXE_yw"))
+        .args(&args)
+        .env_clear()
+        .env("XDG_CONFIG_HOME", env!("CARGO_TARGET_TMPDIR"))
+        .output()
+        .unwrap();
+    if !output.status.success() {
+        return (expected, Err(String::from_utf8(output.stderr).unwrap()));
+    }
+    let written = match redirect {
+        Some(_) => String::from_utf8(output.stdout).unwrap(),
+        None => fs::read_to_string(&actual).unwrap(),
+    };
+    (expected, Ok(written))
+}
+
+#[test]
+fn fixtures_match_their_committed_outputs() {
+    let mut fixtures: Vec<PathBuf> = fs::read_dir("tests/dir3")
+        .unwrap()
+        .map(|entry| entry.unwrap().path())
+        .filter(|path| path.extension().is_some_and(|extension| extension == "yaml"))
+        .collect();
+    fixtures.sort();
+
+    let mut failures = Vec::new();
+    for fixture in fixtures {
+        let content = fs::read_to_string(&fixture).unwrap();
+        let mut lines = content.lines();
+        let Some(header) = lines.next().and_then(|line| line.strip_prefix("## cargo run -- ")) else {
+            continue;
+        };
+        let fails_with = lines.next().and_then(|line| line.strip_prefix("## fails with: "));
+        let (expected, written) = run_fixture(&fixture, header);
+        match (fails_with, written) {
+            (Some(error), Ok(_)) => {
+                failures.push(format!("{}: succeeded, expected to fail with {}", fixture.display(), error))
+            }
+            (Some(error), Err(errors)) if !errors.contains(error) => {
+                failures.push(format!("{}: failed without {}:\n{}", fixture.display(), error, errors))
+            }
+            (Some(_), Err(_)) => {}
+            (None, Err(errors)) => failures.push(format!("{}: failed:\n{}", fixture.display(), errors)),
+            (None, Ok(written)) => match fs::read_to_string(&expected) {
+                Ok(expected_text) if expected_text != written => {
+                    failures.push(format!("{}: differs from {}", fixture.display(), expected.display()))
+                }
+                Ok(_) => {}
+                Err(_) => failures.push(format!("{}: {} is not committed", fixture.display(), expected.display())),
+            },
+        }
+    }
+    assert!(failures.is_empty(), "{}", failures.join("\n"));
+}